@@ -0,0 +1,291 @@
+//! Deprecated preference names that keep working after a key is renamed.
+//!
+//! An alias registered with [`register_alias`] maps an old key to its new name. Reading
+//! the new key falls back to the value stored under the old one until the new key is
+//! written, code still using the old name reads and writes the new key, and every use of
+//! the old name is reported to the hook set with [`on_deprecation`]. The old file is kept
+//! by default, so a downgraded release still finds its value.
+
+use std::{collections::HashMap, fs, io, sync::{Arc, Mutex}};
+
+type Hook = Arc<dyn Fn(&Deprecation) + Send + Sync>;
+
+#[derive(Default)]
+struct AppAliases {
+    aliases: Vec<Alias>,
+    hook: Option<Hook>,
+}
+
+static ALIASES: Mutex<Option<HashMap<String, AppAliases>>> = Mutex::new(None);
+
+/// An old preference name that now refers to another key.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{getpreference, register_alias, savepreference, Alias};
+///
+/// savepreference("myapp_alias_doc", "colour", "blue").unwrap();
+/// register_alias("myapp_alias_doc", Alias::new("colour", "color")).unwrap();
+/// assert_eq!(getpreference("myapp_alias_doc", "color", "red"), "blue");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    old_key: String,
+    new_key: String,
+    remove_old: bool,
+}
+
+impl Alias {
+    /// Declares `old_key` as the former name of `new_key`.
+    pub fn new(old_key: impl Into<String>, new_key: impl Into<String>) -> Alias {
+        Alias { old_key: old_key.into(), new_key: new_key.into(), remove_old: false }
+    }
+
+    /// Removes the file stored under the old name once the new key is written. Off by
+    /// default, which keeps the old value readable by releases that predate the rename.
+    pub fn remove_old(mut self, remove_old: bool) -> Alias {
+        self.remove_old = remove_old;
+        self
+    }
+
+    /// The deprecated name.
+    pub fn old_key(&self) -> &str {
+        &self.old_key
+    }
+
+    /// The name the key has now.
+    pub fn new_key(&self) -> &str {
+        &self.new_key
+    }
+}
+
+/// How a deprecated name was used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeprecationKind {
+    /// The caller passed the old name; the new key was used instead.
+    OldNameUsed,
+    /// The new key is not stored yet, so the value stored under the old name was read.
+    OldValueRead,
+}
+
+/// A use of a deprecated name, reported to the hook set with [`on_deprecation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    /// The app the key belongs to.
+    pub app_name: String,
+    /// The deprecated name.
+    pub old_key: String,
+    /// The name the key has now.
+    pub new_key: String,
+    /// How the deprecated name was used.
+    pub kind: DeprecationKind,
+}
+
+/// Registers a deprecated name for one of the app's preferences.
+///
+/// # Errors
+///
+/// Fails with `InvalidInput` if the old and new names are the same, the old name is
+/// already an alias, or either name takes part in another alias the other way round,
+/// which would chain renames.
+pub fn register_alias(app_name: impl Into<String>, alias: Alias) -> io::Result<()> {
+    if alias.old_key == alias.new_key {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} cannot be an alias of itself", alias.old_key)));
+    }
+    let mut registry = ALIASES.lock().unwrap_or_else(|e| e.into_inner());
+    let app = registry.get_or_insert_with(HashMap::new).entry(app_name.into()).or_default();
+    if let Some(existing) = app.aliases.iter().find(|existing| {
+        existing.old_key == alias.old_key || existing.new_key == alias.old_key || existing.old_key == alias.new_key
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("alias {:?} -> {:?} conflicts with {:?} -> {:?}", alias.old_key, alias.new_key, existing.old_key, existing.new_key),
+        ));
+    }
+    app.aliases.push(alias);
+    Ok(())
+}
+
+/// Sets the function called whenever a deprecated name of the app is used, replacing any
+/// previous one. Use it to log which old names are still around before dropping them.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::on_deprecation;
+///
+/// on_deprecation("myapp", |deprecation| {
+///     eprintln!("{} is deprecated, use {}", deprecation.old_key, deprecation.new_key);
+/// });
+/// ```
+pub fn on_deprecation<F>(app_name: impl Into<String>, hook: F)
+where F: Fn(&Deprecation) + Send + Sync + 'static {
+    let mut registry = ALIASES.lock().unwrap_or_else(|e| e.into_inner());
+    registry.get_or_insert_with(HashMap::new).entry(app_name.into()).or_default().hook = Some(Arc::new(hook));
+}
+
+/// Runs `f` on the app's aliases, returning `None` if it has none.
+fn with_aliases<R>(app_name: &str, f: impl FnOnce(&[Alias], Option<&Hook>) -> R) -> Option<R> {
+    let registry = ALIASES.lock().unwrap_or_else(|e| e.into_inner());
+    let app = registry.as_ref()?.get(app_name)?;
+    if app.aliases.is_empty() {
+        return None;
+    }
+    Some(f(&app.aliases, app.hook.as_ref()))
+}
+
+fn report(app_name: &str, alias: &Alias, kind: DeprecationKind, hook: Option<Hook>) {
+    if let Some(hook) = hook {
+        hook(&Deprecation { app_name: app_name.to_string(), old_key: alias.old_key.clone(), new_key: alias.new_key.clone(), kind });
+    }
+}
+
+/// The key to use for `key`: its new name if `key` is deprecated, or `key` itself.
+pub(crate) fn resolve(app_name: &str, key: &str) -> String {
+    let found = with_aliases(app_name, |aliases, hook| {
+        aliases.iter().find(|alias| alias.old_key == key).map(|alias| (alias.clone(), hook.cloned()))
+    }).flatten();
+    match found {
+        Some((alias, hook)) => {
+            report(app_name, &alias, DeprecationKind::OldNameUsed, hook);
+            alias.new_key
+        },
+        None => key.to_string(),
+    }
+}
+
+/// The value stored under an old name of `key`, if `key` itself is not stored. The old
+/// file is verified like any other read.
+///
+/// # Errors
+///
+/// Fails if an old file exists but cannot be read or does not pass its integrity check.
+pub(crate) fn fallback(app_name: &str, key: &str) -> io::Result<Option<String>> {
+    let (old, hook) = match with_aliases(app_name, |aliases, hook| {
+        let old: Vec<Alias> = aliases.iter().filter(|alias| alias.new_key == key).cloned().collect();
+        (old, hook.cloned())
+    }) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    if old.is_empty() || crate::namespace_path(app_name, &format!("{}.txt", key))?.exists() {
+        return Ok(None);
+    }
+    for alias in old {
+        let path = crate::namespace_path(app_name, &format!("{}.txt", alias.old_key))?;
+        let value = match fs::read_to_string(&path) {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        crate::verify_integrity(&path, value.as_bytes())?;
+        report(app_name, &alias, DeprecationKind::OldValueRead, hook);
+        return Ok(Some(value));
+    }
+    Ok(None)
+}
+
+/// Removes the files stored under the old names of `key`: those of aliases created with
+/// `remove_old` after `key` was written, or all of them when `key` is cleared. Returns
+/// whether any file was removed.
+pub(crate) fn remove_old(app_name: &str, key: &str, cleared: bool) -> io::Result<bool> {
+    let old: Vec<Alias> = with_aliases(app_name, |aliases, _| {
+        aliases.iter().filter(|alias| alias.new_key == key && (cleared || alias.remove_old)).cloned().collect()
+    }).unwrap_or_default();
+    let mut removed = false;
+    for alias in old {
+        let path = crate::namespace_path(app_name, &format!("{}.txt", alias.old_key))?;
+        match crate::tracked_write(app_name, &path, || fs::remove_file(&path)) {
+            Ok(()) => removed = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod alias_test {
+    use super::*;
+    use crate::{clearpreference, getpreference, getpreferencenodefault, savepreference, Key, Store};
+
+    fn events(app_name: &str) -> Arc<Mutex<Vec<(String, DeprecationKind)>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        on_deprecation(app_name, move |deprecation| {
+            recorded.lock().unwrap().push((deprecation.old_key.clone(), deprecation.kind));
+        });
+        events
+    }
+
+    #[test]
+    fn test_alias_falls_back_and_redirects() {
+        let app_name = "prefstore_alias_test";
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        savepreference(app_name, "dark_mode", "dark").unwrap();
+        register_alias(app_name, Alias::new("dark_mode", "theme")).unwrap();
+        let events = events(app_name);
+
+        assert_eq!(getpreference(app_name, "theme", "light"), "dark");
+        assert!(!dir.join("theme.txt").exists());
+        assert_eq!(getpreferencenodefault(app_name, "theme").unwrap(), "dark");
+        assert_eq!(Store::new(app_name).unwrap().get(Key::new("theme", String::new())).unwrap(), "dark");
+
+        // Writing through the old name stores the new key and keeps the old file.
+        savepreference(app_name, "dark_mode", "light").unwrap();
+        assert_eq!(fs::read_to_string(dir.join("theme.txt")).unwrap(), "light");
+        assert_eq!(fs::read_to_string(dir.join("dark_mode.txt")).unwrap(), "dark");
+        assert_eq!(getpreference(app_name, "dark_mode", ""), "light");
+
+        // Clearing the key removes the old file too, so the old value does not come back.
+        clearpreference(app_name, "theme").unwrap();
+        assert!(!dir.join("dark_mode.txt").exists());
+        assert_eq!(getpreference(app_name, "theme", "light"), "light");
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            ("dark_mode".to_string(), DeprecationKind::OldValueRead),
+            ("dark_mode".to_string(), DeprecationKind::OldValueRead),
+            ("dark_mode".to_string(), DeprecationKind::OldValueRead),
+            ("dark_mode".to_string(), DeprecationKind::OldNameUsed),
+            ("dark_mode".to_string(), DeprecationKind::OldNameUsed),
+        ]);
+    }
+
+    #[test]
+    fn test_alias_remove_old() {
+        let app_name = "prefstore_alias_remove_test";
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        savepreference(app_name, "fontsize", 10).unwrap();
+        register_alias(app_name, Alias::new("fontsize", "font_size").remove_old(true)).unwrap();
+
+        savepreference(app_name, "font_size", 14).unwrap();
+        assert!(!dir.join("fontsize.txt").exists());
+        assert_eq!(getpreference(app_name, "font_size", 12), "14");
+    }
+
+    #[test]
+    fn test_old_values_are_checked_against_the_schema() {
+        let app_name = "prefstore_alias_schema_test";
+        let _ = fs::remove_dir_all(crate::config_folder_path(app_name).unwrap());
+        savepreference(app_name, "fontsize", "huge").unwrap();
+        register_alias(app_name, Alias::new("fontsize", "font_size")).unwrap();
+        let schema = crate::Schema::new().pref(crate::PrefSpec::new("font_size", crate::PrefType::Int, 12));
+        crate::register_schema(app_name, schema).unwrap();
+        assert_eq!(getpreference(app_name, "font_size", 10), "12");
+        assert_eq!(crate::getschemapreference(app_name, "fontsize").unwrap(), "12");
+    }
+
+    #[test]
+    fn test_invalid_aliases() {
+        let app_name = "prefstore_alias_invalid_test";
+        assert!(register_alias(app_name, Alias::new("a", "a")).is_err());
+        register_alias(app_name, Alias::new("a", "b")).unwrap();
+        assert!(register_alias(app_name, Alias::new("a", "c")).is_err());
+        assert!(register_alias(app_name, Alias::new("b", "c")).is_err());
+        assert!(register_alias(app_name, Alias::new("c", "a")).is_err());
+        register_alias(app_name, Alias::new("c", "b")).unwrap();
+    }
+}
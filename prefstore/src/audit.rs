@@ -0,0 +1,457 @@
+//! An append-only log of every change made through the prefstore API.
+//!
+//! Once an app has an audit configuration, every write that changes a value, from
+//! `savepreference` to `clearall`, appends a line to a hidden file in the app's folder,
+//! `.prefstore-audit`. Each line holds the time, the process id, the configured actor, the
+//! key and its old and new value. The log is rotated to `.prefstore-audit.1`,
+//! `.prefstore-audit.2` and so on once it grows past a size limit, and read back with
+//! [`read_audit`].
+//!
+//! Values of keys marked with [`mark_sensitive`](crate::mark_sensitive) are never written
+//! to the log.
+//!
+//! [`Queue`](crate::Queue) items are not logged. They are work in flight rather than
+//! stored values, and every dequeue and acknowledgement would otherwise add entries.
+//! [`PersistentSet`](crate::PersistentSet) and [`PersistentMap`](crate::PersistentMap)
+//! elements are logged like any other key.
+
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::encoding::{escape, fnv1a, unescape};
+
+const LOG_FILE: &str = ".prefstore-audit";
+const LOCK_FILE: &str = ".prefstore-audit.lock";
+
+static AUDITS: Mutex<Option<HashMap<String, Audit>>> = Mutex::new(None);
+
+/// How the values of changed keys are written to the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditValues {
+    /// The values themselves.
+    Full,
+    /// A 64-bit FNV-1a hash of each value, which shows whether two values differ without
+    /// writing them out.
+    ///
+    /// FNV-1a is not a cryptographic hash. Anyone who can read the log can find a short or
+    /// guessable value, such as a PIN or a word, by hashing candidates until one matches.
+    /// Use it to keep values out of plain sight, and mark keys holding secrets with
+    /// [`mark_sensitive`](crate::mark_sensitive) so they are not logged at all.
+    Hashed,
+}
+
+/// The audit configuration of an app.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{set_audit, Audit, AuditValues};
+///
+/// set_audit("myapp", Audit::new().actor("installer").values(AuditValues::Hashed)).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Audit {
+    actor: Option<String>,
+    values: AuditValues,
+    max_bytes: u64,
+    keep: u32,
+}
+
+impl Default for Audit {
+    fn default() -> Audit {
+        Audit::new()
+    }
+}
+
+impl Audit {
+    /// Logs full values without an actor, rotating the log at 1 MiB and keeping 5 rotated logs.
+    pub fn new() -> Audit {
+        Audit { actor: None, values: AuditValues::Full, max_bytes: 1 << 20, keep: 5 }
+    }
+
+    /// Records `actor`, such as a user or tool name, as the author of the changes.
+    pub fn actor(mut self, actor: impl Into<String>) -> Audit {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    /// Sets how values are written.
+    pub fn values(mut self, values: AuditValues) -> Audit {
+        self.values = values;
+        self
+    }
+
+    /// Rotates the log once it is larger than `max_bytes`.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Audit {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Keeps `keep` rotated logs, removing older ones. With 0 the log is discarded on rotation.
+    pub fn keep(mut self, keep: u32) -> Audit {
+        self.keep = keep;
+        self
+    }
+}
+
+/// Starts logging the changes made to an app, replacing any previous configuration. Call
+/// it again to change the actor.
+///
+/// # Errors
+///
+/// Fails if there is no system configuration directory.
+pub fn set_audit(app_name: impl Into<String>, audit: Audit) -> io::Result<()> {
+    let app_name = app_name.into();
+    crate::config_folder_path(&app_name)?;
+    AUDITS.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).insert(app_name, audit);
+    Ok(())
+}
+
+/// Stops logging the changes made to an app. The existing log is kept.
+pub fn stop_audit(app_name: &str) {
+    if let Some(audits) = AUDITS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        audits.remove(app_name);
+    }
+}
+
+/// A value as written to the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditValue {
+    /// The value itself.
+    Value(String),
+    /// The hash of the value, see [`AuditValues::Hashed`].
+    Hash(String),
+    /// The value of a sensitive key, which is not logged.
+    Redacted,
+}
+
+/// A change read back from the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// When the change was made, to the millisecond.
+    pub time: SystemTime,
+    /// The id of the process that made the change.
+    pub pid: u32,
+    /// The actor configured when the change was made.
+    pub actor: Option<String>,
+    /// The key that changed, relative to the app's folder, such as `"theme.txt"`.
+    pub key: String,
+    /// The value before the change, or `None` if the key did not exist.
+    pub old: Option<AuditValue>,
+    /// The value after the change, or `None` if the key was removed.
+    pub new: Option<AuditValue>,
+}
+
+/// Selects entries of the audit log. An empty query selects every entry.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::AuditQuery;
+/// use std::time::{Duration, SystemTime};
+///
+/// let last_hour = AuditQuery::new().key("network/*").since(SystemTime::now() - Duration::from_secs(3600));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    key: Option<glob::Pattern>,
+    actor: Option<String>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl AuditQuery {
+    /// A query selecting every entry.
+    pub fn new() -> AuditQuery {
+        AuditQuery::default()
+    }
+
+    /// Selects changes to keys matching a glob pattern, matched as by
+    /// [`mark_sensitive`](crate::mark_sensitive). An invalid pattern matches nothing.
+    pub fn key(mut self, pattern: &str) -> AuditQuery {
+        self.key = Some(glob::Pattern::new(pattern).unwrap_or_else(|_| glob::Pattern::new("[]").unwrap()));
+        self
+    }
+
+    /// Selects changes made by `actor`.
+    pub fn actor(mut self, actor: impl Into<String>) -> AuditQuery {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    /// Selects changes made at or after `time`.
+    pub fn since(mut self, time: SystemTime) -> AuditQuery {
+        self.since = Some(time);
+        self
+    }
+
+    /// Selects changes made before `time`.
+    pub fn until(mut self, time: SystemTime) -> AuditQuery {
+        self.until = Some(time);
+        self
+    }
+
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.key.as_ref().is_none_or(|pattern| crate::key_matches(pattern, &entry.key))
+            && self.actor.as_ref().is_none_or(|actor| entry.actor.as_ref() == Some(actor))
+            && self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time < until)
+    }
+}
+
+/// Returns the entries of the app's audit log selected by `query`, oldest first,
+/// including the rotated logs that are still kept.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{read_audit, savepreference, set_audit, Audit, AuditQuery, AuditValue};
+///
+/// set_audit("myapp_audit_doc", Audit::new().actor("settings-ui")).unwrap();
+/// savepreference("myapp_audit_doc", "theme", "dark").unwrap();
+/// let entries = read_audit("myapp_audit_doc", &AuditQuery::new().key("theme")).unwrap();
+/// assert_eq!(entries.last().unwrap().new, Some(AuditValue::Value("dark".to_string())));
+/// ```
+///
+/// # Errors
+///
+/// Fails if a log cannot be read or holds a malformed line.
+pub fn read_audit(app_name: impl Into<String>, query: &AuditQuery) -> io::Result<Vec<AuditEntry>> {
+    let app_dir = crate::config_folder_path(&app_name.into())?;
+    let mut logs: Vec<PathBuf> = Vec::new();
+    for n in 1.. {
+        let path = rotated_path(&app_dir, n);
+        if !path.exists() {
+            break;
+        }
+        logs.push(path);
+    }
+    logs.reverse();
+    logs.push(app_dir.join(LOG_FILE));
+
+    let mut entries = Vec::new();
+    for path in logs {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(file).lines() {
+            let entry = parse_line(&line?)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed audit entry in {}", path.display())))?;
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Rotates the app's audit log now, whatever its size.
+///
+/// # Errors
+///
+/// Fails if the app has no audit configuration, or the logs cannot be renamed.
+pub fn rotate_audit(app_name: impl Into<String>) -> io::Result<()> {
+    let app_name = app_name.into();
+    let audit = audit_of(&app_name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no audit configuration", app_name)))?;
+    let app_dir = crate::config_folder_path(&app_name)?;
+    let _lock = lock(&app_dir)?;
+    rotate(&app_dir, audit.keep)
+}
+
+pub(crate) fn is_enabled(app_name: &str) -> bool {
+    audit_of(app_name).is_some()
+}
+
+/// Whether a folder entry is one of the audit logs.
+pub(crate) fn is_audit_file(name: &std::ffi::OsStr) -> bool {
+    name.to_str().is_some_and(|name| name.starts_with(LOG_FILE))
+}
+
+/// Appends the change of `key` to the app's log, rotating it first if it is too large.
+pub(crate) fn record(app_name: &str, key: &str, old: Option<&str>, new: Option<&str>) -> io::Result<()> {
+    let audit = match audit_of(app_name) {
+        Some(audit) => audit,
+        None => return Ok(()),
+    };
+    let app_dir = crate::config_folder_path(app_name)?;
+    let sensitive = crate::is_sensitive(app_name, key);
+    let logged = |value: &str| match audit.values {
+        _ if sensitive => AuditValue::Redacted,
+        AuditValues::Full => AuditValue::Value(value.to_string()),
+        AuditValues::Hashed => AuditValue::Hash(format!("{:016x}", fnv1a(value.as_bytes()))),
+    };
+    let entry = AuditEntry {
+        time: SystemTime::now(),
+        pid: std::process::id(),
+        actor: audit.actor.clone(),
+        key: key.to_string(),
+        old: old.map(logged),
+        new: new.map(logged),
+    };
+
+    let _lock = lock(&app_dir)?;
+    let path = app_dir.join(LOG_FILE);
+    if fs::symlink_metadata(&path).map(|metadata| metadata.len() > audit.max_bytes).unwrap_or(false) {
+        rotate(&app_dir, audit.keep)?;
+    }
+    let mut log = crate::permissions::open(&path, OpenOptions::new().create(true).append(true))?;
+    log.write_all(format_line(&entry).as_bytes())
+}
+
+fn audit_of(app_name: &str) -> Option<Audit> {
+    AUDITS.lock().unwrap_or_else(|e| e.into_inner()).as_ref()?.get(app_name).cloned()
+}
+
+/// Locks the app's logs against writers in other threads and processes until dropped.
+fn lock(app_dir: &Path) -> io::Result<File> {
+    let lock = crate::permissions::open(&app_dir.join(LOCK_FILE), OpenOptions::new().create(true).truncate(false).write(true))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+fn rotated_path(app_dir: &Path, n: u32) -> PathBuf {
+    app_dir.join(format!("{}.{}", LOG_FILE, n))
+}
+
+fn rotate(app_dir: &Path, keep: u32) -> io::Result<()> {
+    let ignore_not_found = |result: io::Result<()>| match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+    if keep == 0 {
+        return ignore_not_found(fs::remove_file(app_dir.join(LOG_FILE)));
+    }
+    ignore_not_found(fs::remove_file(rotated_path(app_dir, keep)))?;
+    for n in (1..keep).rev() {
+        ignore_not_found(fs::rename(rotated_path(app_dir, n), rotated_path(app_dir, n + 1)))?;
+    }
+    ignore_not_found(fs::rename(app_dir.join(LOG_FILE), rotated_path(app_dir, 1)))
+}
+
+// A line holds tab-separated fields: milliseconds since the Unix epoch, process id, actor,
+// key, old value and new value. The actor and values are `-` when absent, and otherwise
+// start with `=` for text, `#` for a hash or `*` for a redacted value.
+
+fn format_line(entry: &AuditEntry) -> String {
+    let millis = entry.time.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let value = |value: &Option<AuditValue>| match value {
+        None => "-".to_string(),
+        Some(AuditValue::Value(value)) => format!("={}", escape(value)),
+        Some(AuditValue::Hash(hash)) => format!("#{}", hash),
+        Some(AuditValue::Redacted) => "*".to_string(),
+    };
+    let actor = entry.actor.as_ref().map_or("-".to_string(), |actor| format!("={}", escape(actor)));
+    format!("{}\t{}\t{}\t{}\t{}\t{}\n", millis, entry.pid, actor, escape(&entry.key), value(&entry.old), value(&entry.new))
+}
+
+fn parse_line(line: &str) -> Option<AuditEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [millis, pid, actor, key, old, new] = fields[..] else { return None };
+    let value = |field: &str| -> Option<Option<AuditValue>> {
+        match field.split_at_checked(1)? {
+            ("-", "") => Some(None),
+            ("=", value) => Some(Some(AuditValue::Value(unescape(value)?))),
+            ("#", hash) => Some(Some(AuditValue::Hash(hash.to_string()))),
+            ("*", "") => Some(Some(AuditValue::Redacted)),
+            _ => None,
+        }
+    };
+    let actor = match actor.split_at_checked(1)? {
+        ("-", "") => None,
+        ("=", actor) => Some(unescape(actor)?),
+        _ => return None,
+    };
+    Some(AuditEntry {
+        time: UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+        pid: pid.parse().ok()?,
+        actor,
+        key: unescape(key)?,
+        old: value(old)?,
+        new: value(new)?,
+    })
+}
+
+#[cfg(test)]
+mod audit_test {
+    use super::*;
+    use crate::*;
+
+    fn fresh(app_name: &str) -> &str {
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        app_name
+    }
+
+    fn value(value: &str) -> Option<AuditValue> {
+        Some(AuditValue::Value(value.to_string()))
+    }
+
+    #[test]
+    fn test_every_write_is_logged() {
+        let app_name = fresh("prefstore_audit_test");
+        set_audit(app_name, Audit::new().actor("admin")).unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        savepreference(app_name, "theme", "light\tmode\n").unwrap();
+        savepreference(app_name, "theme", "light\tmode\n").unwrap();
+        savecustom(app_name, "notes/todo.md", "x").unwrap();
+        mark_sensitive(app_name, "token").unwrap();
+        savepreference(app_name, "token", "s3cr3t").unwrap();
+        set_audit(app_name, Audit::new().values(AuditValues::Hashed)).unwrap();
+        clearall(app_name, "txt").unwrap();
+
+        let entries = read_audit(app_name, &AuditQuery::new()).unwrap();
+        let changes: Vec<_> = entries.iter().map(|e| (e.actor.as_deref(), e.key.as_str(), e.old.clone(), e.new.clone())).collect();
+        assert_eq!(changes, vec![
+            (Some("admin"), "theme.txt", None, value("dark")),
+            (Some("admin"), "theme.txt", value("dark"), value("light\tmode\n")),
+            (Some("admin"), "notes/todo.md", None, value("x")),
+            (Some("admin"), "token.txt", None, Some(AuditValue::Redacted)),
+            (None, "theme.txt", Some(AuditValue::Hash(format!("{:016x}", fnv1a(b"light\tmode\n")))), None),
+            (None, "token.txt", Some(AuditValue::Redacted), None),
+        ]);
+        assert!(entries.iter().all(|e| e.pid == std::process::id()));
+
+        let theme = read_audit(app_name, &AuditQuery::new().key("theme").actor("admin")).unwrap();
+        assert_eq!(theme.len(), 2);
+        let future = read_audit(app_name, &AuditQuery::new().since(SystemTime::now() + Duration::from_secs(60))).unwrap();
+        assert!(future.is_empty());
+        // The log is not a value of the app.
+        assert!(getall(app_name).unwrap().is_empty());
+        stop_audit(app_name);
+    }
+
+    #[test]
+    fn test_collections_are_logged_and_queues_are_not() {
+        let app_name = fresh("prefstore_audit_collections_test");
+        set_audit(app_name, Audit::new()).unwrap();
+        let map: crate::PersistentMap<String, i32> = crate::PersistentMap::open(app_name, "scores").unwrap();
+        map.insert(&"ada".to_string(), &3).unwrap();
+        let queue = crate::Queue::open(app_name, "jobs").unwrap();
+        queue.enqueue("job").unwrap();
+        let lease = queue.dequeue().unwrap().unwrap();
+        queue.ack(lease).unwrap();
+
+        let keys: Vec<String> = read_audit(app_name, &AuditQuery::new()).unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec!["scores/ada"]);
+    }
+
+    #[test]
+    fn test_log_is_rotated() {
+        let app_name = fresh("prefstore_audit_rotate_test");
+        set_audit(app_name, Audit::new().max_bytes(1).keep(2)).unwrap();
+        for n in 0..4 {
+            savepreference(app_name, "count", n).unwrap();
+        }
+        let dir = config_folder_path(app_name).unwrap();
+        assert!(rotated_path(&dir, 2).exists());
+        assert!(!rotated_path(&dir, 3).exists());
+        let kept: Vec<_> = read_audit(app_name, &AuditQuery::new()).unwrap().into_iter().map(|e| e.new).collect();
+        assert_eq!(kept, vec![value("1"), value("2"), value("3")]);
+
+        rotate_audit(app_name).unwrap();
+        assert!(!dir.join(LOG_FILE).exists());
+        stop_audit(app_name);
+        assert!(rotate_audit(app_name).is_err());
+    }
+}
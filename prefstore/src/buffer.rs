@@ -0,0 +1,221 @@
+//! On-disk ring buffer used by `savebuffer` and `getbuffer`.
+//!
+//! A buffer file starts with a fixed-width header that records the byte offset of the
+//! oldest live entry and the number of live entries. Pushing a value appends it to the
+//! end of the file and, once the buffer is over capacity, moves the offset past the
+//! oldest entries instead of rewriting the file. The space left behind is reclaimed by
+//! an occasional in-place compaction, so a push costs a single open no matter how
+//! large the buffer is.
+//!
+//! Files written by older versions (plain lines, no header) are still readable and are
+//! converted the first time something is pushed to them.
+
+use std::{fs::{File, OpenOptions}, io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write}, path::Path};
+
+const MAGIC: &str = "#prefstore-buffer ";
+/// `MAGIC`, a 20 digit offset, a space, a 10 digit count and a newline.
+const HEADER_LEN: u64 = (MAGIC.len() + 20 + 1 + 10 + 1) as u64;
+/// Dead bytes are only reclaimed once there are at least this many of them.
+const COMPACT_MIN: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    start: u64,
+    count: u64,
+}
+
+impl Header {
+    fn empty() -> Header {
+        Header { start: HEADER_LEN, count: 0 }
+    }
+
+    fn encode(&self) -> String {
+        format!("{}{:020} {:010}\n", MAGIC, self.start, self.count)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Header> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let rest = text.strip_prefix(MAGIC)?.strip_suffix('\n')?;
+        let (start, count) = rest.split_once(' ')?;
+        let header = Header { start: start.parse().ok()?, count: count.parse().ok()? };
+        if header.start < HEADER_LEN {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// Reads the header at the start of `file`, or `None` if the file has no header.
+fn read_header(file: &mut File) -> io::Result<Option<Header>> {
+    let mut bytes = vec![0u8; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match file.read(&mut bytes[filled..])? {
+            0 => return Ok(None),
+            n => filled += n,
+        }
+    }
+    Ok(Header::decode(&bytes))
+}
+
+fn write_header(file: &mut File, header: &Header) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(header.encode().as_bytes())
+}
+
+/// Rewrites a header-less file as a buffer file holding the same non-empty lines.
+fn convert_legacy(file: &mut File) -> io::Result<Header> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+
+    let mut header = Header::empty();
+    let mut body = String::new();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        body.push_str(line);
+        body.push('\n');
+        header.count += 1;
+    }
+
+    file.set_len(0)?;
+    write_header(file, &header)?;
+    file.write_all(body.as_bytes())?;
+    Ok(header)
+}
+
+/// Appends `value` to the buffer at `path`, dropping the oldest entries so that at most
+/// `capacity` remain. Every non-empty line of `value` becomes its own entry.
+pub(crate) fn push(path: &Path, value: &str, capacity: usize) -> io::Result<()> {
+    let entries: Vec<&str> = value.lines().filter(|line| !line.is_empty()).collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut file = crate::permissions::open(path, OpenOptions::new().read(true).write(true).create(true))?;
+    file.lock()?;
+
+    let file_len = file.metadata()?.len();
+    let mut header = match read_header(&mut file)? {
+        Some(header) => header,
+        None if file_len == 0 => {
+            let header = Header::empty();
+            write_header(&mut file, &header)?;
+            header
+        },
+        None => convert_legacy(&mut file)?,
+    };
+
+    let mut appended = String::new();
+    for entry in &entries {
+        appended.push_str(entry);
+        appended.push('\n');
+    }
+    let mut end = file.seek(SeekFrom::End(0))?;
+    file.write_all(appended.as_bytes())?;
+    end += appended.len() as u64;
+    header.count += entries.len() as u64;
+
+    // Skip over the oldest entries rather than deleting them.
+    if header.count > capacity as u64 {
+        file.seek(SeekFrom::Start(header.start))?;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = Vec::new();
+        while header.count > capacity as u64 {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            header.start += read as u64;
+            header.count -= 1;
+        }
+    }
+
+    // Reclaim the skipped space once it outweighs the live entries. The live region is
+    // copied into the dead region in front of it, which never overlaps the original, so
+    // an interrupted compaction leaves the old header pointing at intact data.
+    let dead = header.start - HEADER_LEN;
+    let live = end - header.start;
+    if dead >= COMPACT_MIN && dead > live {
+        let mut live_bytes = vec![0u8; live as usize];
+        file.seek(SeekFrom::Start(header.start))?;
+        file.read_exact(&mut live_bytes)?;
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+        file.write_all(&live_bytes)?;
+        header.start = HEADER_LEN;
+        write_header(&mut file, &header)?;
+        file.set_len(HEADER_LEN + live)?;
+    } else {
+        write_header(&mut file, &header)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the live entries of the buffer at `path`, oldest first. A missing file is an
+/// empty buffer, and a file without a header is read as plain lines.
+pub(crate) fn read(path: &Path) -> io::Result<Vec<String>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    file.lock_shared()?;
+
+    let header = read_header(&mut file)?;
+    let start = header.map(|header| header.start).unwrap_or(0);
+    file.seek(SeekFrom::Start(start))?;
+    let mut lines = BufReader::new(&mut file).lines().collect::<io::Result<Vec<String>>>()?;
+
+    // An interrupted push can leave an entry the header does not count yet.
+    if let Some(header) = header {
+        let excess = lines.len().saturating_sub(header.count as usize);
+        lines.drain(..excess);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod buffer_test {
+    use super::*;
+    use std::fs::{create_dir_all, remove_file};
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join("prefstore_buffer_test");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_push_keeps_last_entries() {
+        let path = test_path("ring");
+        for value in ["yu", "", "yu3", "yu4", "yu5", "yu6"] {
+            push(&path, value, 3).unwrap();
+        }
+        assert_eq!(read(&path).unwrap(), vec!["yu4", "yu5", "yu6"]);
+    }
+
+    #[test]
+    fn test_legacy_file_is_converted() {
+        let path = test_path("legacy");
+        std::fs::write(&path, "one\ntwo\n\nthree\n").unwrap();
+        assert_eq!(read(&path).unwrap(), vec!["one", "two", "", "three"]);
+
+        push(&path, "four", 3).unwrap();
+        assert_eq!(read(&path).unwrap(), vec!["two", "three", "four"]);
+    }
+
+    #[test]
+    fn test_file_is_compacted() {
+        let path = test_path("compact");
+        for i in 0..5000 {
+            push(&path, format!("entry {}", i).as_str(), 10).unwrap();
+        }
+        let expected: Vec<String> = (4990..5000).map(|i| format!("entry {}", i)).collect();
+        assert_eq!(read(&path).unwrap(), expected);
+        assert!(std::fs::metadata(&path).unwrap().len() < HEADER_LEN + 2 * COMPACT_MIN);
+    }
+}
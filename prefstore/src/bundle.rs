@@ -0,0 +1,491 @@
+//! Exporting an app's values to a single portable bundle and importing them elsewhere.
+//!
+//! A bundle holds every value of the app, text and binary, with its key, and the schema
+//! version recorded by migrations. Values of keys marked with
+//! [`mark_sensitive`](crate::mark_sensitive) are left out unless revealed; the bundle
+//! still lists their keys, and importing it keeps whatever value those keys have.
+//!
+//! The JSON format is always available:
+//!
+//! ```json
+//! {
+//!   "prefstore_bundle": 1,
+//!   "app": "myapp",
+//!   "schema_version": 2,
+//!   "values": {
+//!     "theme.txt": {"text": "dark"},
+//!     "icons/logo.png": {"hex": "89504e47"},
+//!     "api_token.txt": {"redacted": true}
+//!   }
+//! }
+//! ```
+//!
+//! With the `tar` feature, a bundle can also be a tar archive holding each value as a file
+//! at its key, and a `.prefstore-bundle` file listing the schema version and redacted keys.
+
+use std::{collections::BTreeMap, fmt::Write as _, fs, io::{self, Read, Write}};
+
+const BUNDLE_VERSION: u32 = 1;
+#[cfg(feature = "tar")]
+const MANIFEST_FILE: &str = ".prefstore-bundle";
+
+/// The format of a bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A JSON document. Text values are stored as strings, other values in hexadecimal.
+    Json,
+    /// A tar archive, available with the `tar` feature.
+    #[cfg(feature = "tar")]
+    Tar,
+}
+
+/// Options for [`export_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Writes the values of sensitive keys instead of leaving them out.
+    pub reveal_sensitive: bool,
+}
+
+/// How [`import`] treats the values the app already has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Writes the values of the bundle and keeps the app's other values. The app's schema
+    /// version is left alone.
+    Merge,
+    /// Makes the app hold exactly the values of the bundle, removing the others, and sets
+    /// the schema version of the bundle. A snapshot is taken first, see
+    /// [`list_snapshots`](crate::list_snapshots).
+    Replace,
+}
+
+/// Options for [`import_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Reports what importing would change without changing anything.
+    pub dry_run: bool,
+}
+
+/// The keys an import changed, or would change in a dry run, each in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Keys the app did not have.
+    pub added: Vec<String>,
+    /// Keys whose value changed.
+    pub changed: Vec<String>,
+    /// Keys removed because the bundle does not have them, in `Replace` mode.
+    pub removed: Vec<String>,
+    /// Keys whose value was redacted in the bundle, which were left as they are.
+    pub skipped: Vec<String>,
+}
+
+struct Bundle {
+    app_name: String,
+    schema_version: u32,
+    /// The value of each key, or `None` if it was redacted.
+    values: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+/// Writes every value of the app to `writer` as a bundle in the given format, leaving out
+/// the values of sensitive keys. Returns how many keys were written.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{export, savepreference, ExportFormat};
+///
+/// savepreference("myapp_export_doc", "theme", "dark").unwrap();
+/// let mut bundle = Vec::new();
+/// export("myapp_export_doc", &mut bundle, ExportFormat::Json).unwrap();
+/// assert!(String::from_utf8(bundle).unwrap().contains(r#""theme.txt": {"text": "dark"}"#));
+/// ```
+///
+/// # Errors
+///
+/// Fails if a value cannot be read or the bundle cannot be written.
+pub fn export(app_name: impl Into<String>, writer: impl Write, format: ExportFormat) -> io::Result<usize> {
+    export_with(app_name, writer, format, &ExportOptions::default())
+}
+
+/// Like [`export`], with options.
+pub fn export_with(app_name: impl Into<String>, mut writer: impl Write, format: ExportFormat, options: &ExportOptions) -> io::Result<usize> {
+    let app_name = app_name.into();
+    let app_dir = crate::config_folder_path(&app_name)?;
+    let schema_version = crate::Store::new(app_name.as_str())?.schema_version()?;
+    let mut values = BTreeMap::new();
+    for key in crate::snapshot::data_files(&app_dir)? {
+        let path = app_dir.join(&key);
+        let value = if options.reveal_sensitive || !crate::is_sensitive(&app_name, &key) {
+            let contents = fs::read(&path)?;
+            crate::verify_integrity(&path, &contents)?;
+            Some(contents)
+        } else {
+            None
+        };
+        values.insert(key, value);
+    }
+    let bundle = Bundle { app_name, schema_version, values };
+    match format {
+        ExportFormat::Json => writer.write_all(write_json(&bundle).as_bytes())?,
+        #[cfg(feature = "tar")]
+        ExportFormat::Tar => write_tar(&bundle, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(bundle.values.len())
+}
+
+/// Reads a bundle written by [`export`] from `reader` and writes its values to the app.
+/// The format is detected from the contents. Every change is made through the prefstore
+/// API, so it reaches the app's observers, audit log and history.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{export, getpreference, import, savepreference, ExportFormat, ImportMode};
+///
+/// savepreference("myapp_import_doc_old", "theme", "dark").unwrap();
+/// let mut bundle = Vec::new();
+/// export("myapp_import_doc_old", &mut bundle, ExportFormat::Json).unwrap();
+/// import("myapp_import_doc_new", bundle.as_slice(), ImportMode::Merge).unwrap();
+/// assert_eq!(getpreference("myapp_import_doc_new", "theme", ""), "dark");
+/// ```
+///
+/// # Errors
+///
+/// Fails with `InvalidData` if the bundle is malformed or has a key outside the app's
+/// folder, with `InvalidInput` if the app's schema does not accept one of its values, in
+/// which case nothing is written, or if a value cannot be written.
+pub fn import(app_name: impl Into<String>, reader: impl Read, mode: ImportMode) -> io::Result<ImportReport> {
+    import_with(app_name, reader, mode, &ImportOptions::default())
+}
+
+/// Like [`import`], with options.
+pub fn import_with(app_name: impl Into<String>, mut reader: impl Read, mode: ImportMode, options: &ImportOptions) -> io::Result<ImportReport> {
+    let app_name = app_name.into();
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let bundle = read_bundle(&data)?;
+    let app_dir = crate::config_folder_path(&app_name)?;
+
+    let mut report = ImportReport::default();
+    for (key, value) in &bundle.values {
+        check_key(key)?;
+        let value = match value {
+            Some(value) => value,
+            None => {
+                report.skipped.push(key.clone());
+                continue;
+            },
+        };
+        if let Some(spec) = crate::schema::spec_for_file(&app_name, key) {
+            let text = std::str::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            spec.parse(text)?;
+        }
+        match fs::read(app_dir.join(key)) {
+            Ok(current) if current == *value => {},
+            Ok(_) => report.changed.push(key.clone()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => report.added.push(key.clone()),
+            Err(e) => return Err(e),
+        }
+    }
+    if mode == ImportMode::Replace {
+        report.removed = crate::snapshot::data_files(&app_dir)?.into_iter().filter(|key| !bundle.values.contains_key(key)).collect();
+    }
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    if mode == ImportMode::Replace {
+        crate::snapshot::snapshot_before(&app_name, "import")?;
+    }
+    for key in report.added.iter().chain(&report.changed) {
+        let path = app_dir.join(key);
+        if let Some(parent) = path.parent() {
+            crate::permissions::create_dirs(parent)?;
+        }
+        let value = bundle.values[key].as_deref().unwrap_or_default();
+        crate::tracked_write(&app_name, &path, || crate::atomic_write(&path, value))?;
+    }
+    for key in &report.removed {
+        let path = app_dir.join(key);
+        match crate::tracked_write(&app_name, &path, || fs::remove_file(&path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {},
+        }
+    }
+    if mode == ImportMode::Replace {
+        crate::permissions::create_dirs(&app_dir)?;
+        let version = app_dir.join(crate::migrate::VERSION_FILE);
+        if bundle.schema_version == 0 {
+            match fs::remove_file(&version) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        } else {
+            crate::atomic_write(&version, bundle.schema_version.to_string().as_bytes())?;
+        }
+    }
+    Ok(report)
+}
+
+/// Rejects keys that would write outside the app's folder or over its hidden files.
+fn check_key(key: &str) -> io::Result<()> {
+    let valid = !key.is_empty() && key.split('/').all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'));
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid key {:?} in bundle", key)))
+    }
+}
+
+fn read_bundle(data: &[u8]) -> io::Result<Bundle> {
+    #[cfg(feature = "tar")]
+    if data.get(257..262) == Some(b"ustar") {
+        return read_tar(data);
+    }
+    let document: serde_json::Value = serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    read_json(&document).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed bundle"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn write_json(bundle: &Bundle) -> String {
+    let mut json = String::new();
+    let _ = writeln!(json, "{{");
+    let _ = writeln!(json, "  \"prefstore_bundle\": {},", BUNDLE_VERSION);
+    let _ = writeln!(json, "  \"app\": {},", json_string(&bundle.app_name));
+    let _ = writeln!(json, "  \"schema_version\": {},", bundle.schema_version);
+    let _ = write!(json, "  \"values\": {{");
+    for (index, (key, value)) in bundle.values.iter().enumerate() {
+        let value = match value {
+            None => "{\"redacted\": true}".to_string(),
+            Some(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => format!("{{\"text\": {}}}", json_string(text)),
+                Err(_) => format!("{{\"hex\": \"{}\"}}", to_hex(bytes)),
+            },
+        };
+        let separator = if index == 0 { "" } else { "," };
+        let _ = write!(json, "{}\n    {}: {}", separator, json_string(key), value);
+    }
+    let _ = writeln!(json, "{}}}", if bundle.values.is_empty() { "" } else { "\n  " });
+    let _ = writeln!(json, "}}");
+    json
+}
+
+fn json_string(text: &str) -> String {
+    serde_json::Value::from(text).to_string()
+}
+
+fn read_json(document: &serde_json::Value) -> Option<Bundle> {
+    if document.get("prefstore_bundle")?.as_u64()? != u64::from(BUNDLE_VERSION) {
+        return None;
+    }
+    let mut values = BTreeMap::new();
+    for (key, value) in document.get("values")?.as_object()? {
+        let fields: Vec<_> = value.as_object()?.iter().collect();
+        let value = match fields.as_slice() {
+            [(kind, serde_json::Value::String(text))] if *kind == "text" => Some(text.as_bytes().to_vec()),
+            [(kind, serde_json::Value::String(hex))] if *kind == "hex" => Some(from_hex(hex)?),
+            [(kind, serde_json::Value::Bool(true))] if *kind == "redacted" => None,
+            _ => return None,
+        };
+        values.insert(key.clone(), value);
+    }
+    Some(Bundle {
+        app_name: document.get("app").and_then(serde_json::Value::as_str).unwrap_or_default().to_string(),
+        schema_version: u32::try_from(document.get("schema_version")?.as_u64()?).ok()?,
+        values,
+    })
+}
+
+#[cfg(feature = "tar")]
+fn write_tar(bundle: &Bundle, writer: &mut impl Write) -> io::Result<()> {
+    let mut manifest = format!("prefstore-bundle {}\napp {}\nschema_version {}\n", BUNDLE_VERSION, crate::encoding::escape(&bundle.app_name), bundle.schema_version);
+    for (key, value) in &bundle.values {
+        if value.is_none() {
+            let _ = writeln!(manifest, "redacted {}", crate::encoding::escape(key));
+        }
+    }
+    let mtime = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut archive = tar::Builder::new(writer);
+    let mut append = |path: &str, contents: &[u8]| -> io::Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_entry_type(tar::EntryType::Regular);
+        archive.append_data(&mut header, path, contents)
+    };
+    append(MANIFEST_FILE, manifest.as_bytes())?;
+    for (key, value) in &bundle.values {
+        if let Some(value) = value {
+            append(key, value)?;
+        }
+    }
+    archive.finish()
+}
+
+#[cfg(feature = "tar")]
+fn read_tar(data: &[u8]) -> io::Result<Bundle> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed bundle manifest");
+    let mut manifest = None;
+    let mut values = BTreeMap::new();
+    for entry in tar::Archive::new(data).entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        if !entry.header().entry_type().is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bundle holds an entry that is not a file"));
+        }
+        let path = entry.path()?.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid key in bundle"))?.to_string();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        if path == MANIFEST_FILE {
+            manifest = Some(String::from_utf8(contents).map_err(|_| malformed())?);
+        } else {
+            values.insert(path, Some(contents));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bundle has no manifest"))?;
+    let mut lines = manifest.lines();
+    if lines.next() != Some(&format!("prefstore-bundle {}", BUNDLE_VERSION)) {
+        return Err(malformed());
+    }
+    let (mut app_name, mut schema_version) = (String::new(), 0);
+    for line in lines {
+        match line.split_once(' ').ok_or_else(malformed)? {
+            ("app", name) => app_name = crate::encoding::unescape(name).ok_or_else(malformed)?,
+            ("schema_version", version) => schema_version = version.parse().map_err(|_| malformed())?,
+            ("redacted", key) => {
+                values.insert(crate::encoding::unescape(key).ok_or_else(malformed)?, None);
+            },
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(Bundle { app_name, schema_version, values })
+}
+
+#[cfg(test)]
+mod bundle_test {
+    use super::*;
+    use crate::*;
+
+    fn fresh(app_name: &str) -> &str {
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        app_name
+    }
+
+    fn formats() -> Vec<ExportFormat> {
+        vec![
+            ExportFormat::Json,
+            #[cfg(feature = "tar")]
+            ExportFormat::Tar,
+        ]
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        for format in formats() {
+            let from = fresh("prefstore_bundle_from_test");
+            savepreference(from, "theme", "dark \"night\"\n").unwrap();
+            savepreference(from, "window/width", 800).unwrap();
+            savepreference(from, "api_token", "s3cr3t").unwrap();
+            let logo = config_folder_path(from).unwrap().join("icons/logo.png");
+            fs::create_dir_all(logo.parent().unwrap()).unwrap();
+            fs::write(&logo, [0x89, b'P', b'N', b'G', 0xff, 0]).unwrap();
+            mark_sensitive(from, "api_token").unwrap();
+            Store::new(from).unwrap().migrate(&Migrations::new().migration(Migration::new(3))).unwrap();
+
+            let mut bundle = Vec::new();
+            assert_eq!(export(from, &mut bundle, format).unwrap(), 4);
+            assert!(!String::from_utf8_lossy(&bundle).contains("s3cr3t"));
+
+            let to = fresh("prefstore_bundle_to_test");
+            savepreference(to, "theme", "light").unwrap();
+            savepreference(to, "api_token", "mine").unwrap();
+            savepreference(to, "extra", 1).unwrap();
+            let dry_run = ImportOptions { dry_run: true };
+            let report = import_with(to, bundle.as_slice(), ImportMode::Replace, &dry_run).unwrap();
+            assert_eq!(report, ImportReport {
+                added: vec!["icons/logo.png".to_string(), "window/width.txt".to_string()],
+                changed: vec!["theme.txt".to_string()],
+                removed: vec!["extra.txt".to_string()],
+                skipped: vec!["api_token.txt".to_string()],
+            });
+            assert_eq!(getpreference(to, "theme", ""), "light");
+
+            let merged = import(to, bundle.as_slice(), ImportMode::Merge).unwrap();
+            assert!(merged.removed.is_empty());
+            assert_eq!(getpreference(to, "extra", ""), "1");
+            assert_eq!(Store::new(to).unwrap().schema_version().unwrap(), 0);
+
+            import(to, bundle.as_slice(), ImportMode::Replace).unwrap();
+            assert_eq!(getall(to).unwrap(), vec![
+                ("api_token".to_string(), "mine".to_string()),
+                ("theme".to_string(), "dark \"night\"\n".to_string()),
+                ("width".to_string(), "800".to_string()),
+            ]);
+            assert_eq!(fs::read(config_folder_path(to).unwrap().join("icons/logo.png")).unwrap(), fs::read(&logo).unwrap());
+            assert_eq!(Store::new(to).unwrap().schema_version().unwrap(), 3);
+
+            let mut revealed = Vec::new();
+            export_with(from, &mut revealed, format, &ExportOptions { reveal_sensitive: true }).unwrap();
+            import(to, revealed.as_slice(), ImportMode::Merge).unwrap();
+            assert_eq!(getpreference(to, "api_token", ""), "s3cr3t");
+        }
+    }
+
+    #[test]
+    fn test_invalid_bundles() {
+        let app_name = fresh("prefstore_bundle_invalid_test");
+        let bundle = |values: &str| format!("{{\"prefstore_bundle\": 1, \"schema_version\": 0, \"values\": {{{}}}}}", values);
+        for invalid in ["not json".to_string(), bundle(r#""../escape.txt": {"text": "x"}"#), bundle(r#"".prefstore-version": {"text": "9"}"#)] {
+            let error = import(app_name, invalid.as_bytes(), ImportMode::Merge).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        register_schema(app_name, Schema::new().pref(PrefSpec::new("volume", PrefType::Int, 5).range(0.0, 10.0))).unwrap();
+        let out_of_range = bundle(r#""theme.txt": {"text": "dark"}, "volume.txt": {"text": "11"}"#);
+        assert_eq!(import(app_name, out_of_range.as_bytes(), ImportMode::Merge).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(!config_folder_path(app_name).unwrap().join("theme.txt").exists());
+        let invalid = [
+            bundle(r#""theme.txt": {"text": ["dark"]}"#),
+            "{\"a\": ".repeat(100_000),
+            bundle(r#""theme.txt": {"text": "\ud83c\ue000"}"#),
+            r#"{"prefstore_bundle": 1, "schema_version": 2.5, "values": {}}"#.to_string(),
+        ];
+        for invalid in invalid {
+            let error = import(app_name, invalid.as_bytes(), ImportMode::Merge).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", invalid);
+        }
+        let unicode = bundle(r#""theme.txt": {"text": "caf\u00e9 \ud83c\udf75"}"#);
+        import(app_name, unicode.as_bytes(), ImportMode::Merge).unwrap();
+        assert_eq!(getpreference(app_name, "theme", ""), "café 🍵");
+    }
+
+    #[test]
+    fn test_queues_are_not_exported() {
+        let from = fresh("prefstore_bundle_queue_from_test");
+        savepreference(from, "theme", "dark").unwrap();
+        Queue::open(from, "jobs").unwrap().enqueue("first").unwrap();
+        let mut bundle = Vec::new();
+        assert_eq!(export(from, &mut bundle, ExportFormat::Json).unwrap(), 1);
+
+        let to = fresh("prefstore_bundle_queue_to_test");
+        let queue = Queue::open(to, "jobs").unwrap();
+        queue.enqueue("second").unwrap();
+        let report = import(to, bundle.as_slice(), ImportMode::Replace).unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(queue.dequeue().unwrap().unwrap().value, "second");
+    }
+}
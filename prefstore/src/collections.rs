@@ -0,0 +1,416 @@
+//! Persistent set and map collections.
+//!
+//! A collection is a directory inside the app's config folder with one file per element,
+//! named after the element (or map key). Inserting or removing an element touches only
+//! that element's file, so every mutation is atomic and never rewrites unrelated entries.
+//! Elements are stored as text using `ToString` and read back with `FromStr`.
+
+use std::{fmt::Display, fs::{self, OpenOptions, remove_file}, io::{self, Write}, marker::PhantomData, path::{Path, PathBuf}, str::FromStr};
+
+/// File name used for the empty string, which the encoder can never produce otherwise.
+const EMPTY_NAME: &str = "%";
+
+/// Longest encoded name used as a file name. File names are limited to 255 bytes, so longer
+/// keys are stored under a hash of the key instead, with the key on the first line of the file.
+const MAX_NAME_LEN: usize = 200;
+
+/// First character of hashed names, which the encoder never produces.
+const HASHED_PREFIX: char = '~';
+
+/// Encodes `key` as a file name. Lowercase letters, digits, `-` and `_` are kept and every
+/// other byte, uppercase letters included, becomes `%XX`, so names are portable, never
+/// hidden or special, and keys differing only in case stay apart on case-insensitive
+/// file systems.
+fn encode_name(key: &str) -> String {
+    if key.is_empty() {
+        return EMPTY_NAME.to_string();
+    }
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+/// Reverses `encode_name`, or returns `None` for names it could not have produced.
+/// Uppercase letters are accepted, as collections written before they were escaped have them.
+fn decode_name(name: &str) -> Option<String> {
+    if name == EMPTY_NAME {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => bytes.push(byte),
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            _ => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The file holding an element.
+struct Element {
+    path: PathBuf,
+    /// Whether the file is named after a hash, with the key on its first line.
+    hashed: bool,
+}
+
+impl Element {
+    /// Finds the file of `key` in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a long key hashes to the same name as another stored key.
+    fn of(dir: &Path, key: &str) -> io::Result<Element> {
+        let name = encode_name(key);
+        if name.len() <= MAX_NAME_LEN {
+            return Ok(Element { path: dir.join(name), hashed: false });
+        }
+        let path = dir.join(format!("{}{:016x}", HASHED_PREFIX, crate::encoding::fnv1a(key.as_bytes())));
+        match stored_key(&path)? {
+            Some(stored) if stored != key => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("key {:?} hashes to the same file name as key {:?}", key, stored),
+            )),
+            _ => Ok(Element { path, hashed: true }),
+        }
+    }
+
+    /// The contents to store for `key` with `value`.
+    fn contents(&self, key: &str, value: &str) -> String {
+        if self.hashed { format!("{}\n{}", crate::encoding::escape(key), value) } else { value.to_string() }
+    }
+
+    /// Reads the value, or `None` if the element does not exist.
+    fn read(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) if self.hashed => Ok(Some(text.split_once('\n').map_or(String::new(), |(_, value)| value.to_string()))),
+            Ok(text) => Ok(Some(text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The key stored on the first line of a hashed element file, or `None` if there is no file.
+fn stored_key(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let line = text.split('\n').next().unwrap_or_default();
+            crate::encoding::unescape(line).map(Some)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed element file {}", path.display())))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse<T: FromStr>(text: &str) -> io::Result<T> where T::Err: Display {
+    text.parse::<T>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("could not parse {:?}: {}", text, e)))
+}
+
+/// The directory of a collection, validated like a namespace and created if needed.
+fn collection_dir(app_name: &str, name: String) -> io::Result<PathBuf> {
+    if name.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "collection name is empty"));
+    }
+    let dir = crate::namespace_path(app_name, &name)?;
+    crate::permissions::create_dirs(&dir)?;
+    Ok(dir)
+}
+
+/// The keys of the elements in `dir` with their files, sorted by key. Temporary and
+/// foreign files are skipped.
+fn element_files(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut elements = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let key = if name.starts_with(HASHED_PREFIX) { stored_key(&entry.path())? } else { decode_name(&name) };
+        if let Some(key) = key {
+            elements.push((key, entry.path()));
+        }
+    }
+    elements.sort();
+    Ok(elements)
+}
+
+/// Decoded element names in `dir`, sorted.
+fn element_names(dir: &Path) -> io::Result<Vec<String>> {
+    Ok(element_files(dir)?.into_iter().map(|(key, _)| key).collect())
+}
+
+/// A set of values stored in a directory under an app's config folder.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::PersistentSet;
+///
+/// let dismissed: PersistentSet<String> = PersistentSet::open("myapp", "dismissed_notifications").unwrap();
+/// dismissed.insert(&"update-available".to_string()).unwrap();
+/// assert!(dismissed.contains(&"update-available".to_string()).unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PersistentSet<T> {
+    app_name: String,
+    dir: PathBuf,
+    marker: PhantomData<T>,
+}
+
+impl<T: ToString + FromStr> PersistentSet<T> where T::Err: Display {
+    /// Opens the set named `set_name` for the given app, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `app_name`: The name of the app.
+    /// * `set_name`: The name of the set. It is used as a directory name and, like a
+    ///   namespace, may not be empty, absolute or contain `..`.
+    pub fn open(app_name: impl Into<String>, set_name: impl Into<String>) -> io::Result<PersistentSet<T>> {
+        let app_name = app_name.into();
+        let dir = collection_dir(&app_name, set_name.into())?;
+        Ok(PersistentSet { app_name, dir, marker: PhantomData })
+    }
+
+    /// Adds a value to the set. Returns `true` if it was not already present.
+    pub fn insert(&self, value: &T) -> io::Result<bool> {
+        let value = value.to_string();
+        let element = Element::of(&self.dir, &value)?;
+        let path = &element.path;
+        let create = || -> io::Result<()> {
+            let mut file = crate::permissions::open(path, OpenOptions::new().write(true).create_new(true))?;
+            file.write_all(element.contents(&value, "").as_bytes())
+        };
+        match crate::tracked_write(&self.app_name, path, create) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes a value from the set. Returns `true` if it was present.
+    pub fn remove(&self, value: &T) -> io::Result<bool> {
+        let path = Element::of(&self.dir, &value.to_string())?.path;
+        match crate::tracked_write(&self.app_name, &path, || remove_file(&path)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns `true` if the set contains the value.
+    pub fn contains(&self, value: &T) -> io::Result<bool> {
+        Ok(Element::of(&self.dir, &value.to_string())?.path.is_file())
+    }
+
+    /// Returns an iterator over the values in the set, sorted by their text form.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<T>>> {
+        Ok(element_names(&self.dir)?.into_iter().map(|name| parse(&name)))
+    }
+
+    /// Returns the number of values in the set.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(element_names(&self.dir)?.len())
+    }
+
+    /// Returns `true` if the set holds no values.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Removes every value from the set.
+    pub fn clear(&self) -> io::Result<()> {
+        for (_, path) in element_files(&self.dir)? {
+            match crate::tracked_write(&self.app_name, &path, || remove_file(&path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A map from keys to values stored in a directory under an app's config folder. Each
+/// key is a file whose contents are the value.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::PersistentMap;
+///
+/// let editors: PersistentMap<String, String> = PersistentMap::open("myapp", "editors").unwrap();
+/// editors.insert(&"rs".to_string(), &"helix".to_string()).unwrap();
+/// assert_eq!(editors.get(&"rs".to_string()).unwrap(), Some("helix".to_string()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct PersistentMap<K, V> {
+    app_name: String,
+    dir: PathBuf,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K: ToString + FromStr, V: ToString + FromStr> PersistentMap<K, V> where K::Err: Display, V::Err: Display {
+    /// Opens the map named `map_name` for the given app, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `app_name`: The name of the app.
+    /// * `map_name`: The name of the map. It is used as a directory name and, like a
+    ///   namespace, may not be empty, absolute or contain `..`.
+    pub fn open(app_name: impl Into<String>, map_name: impl Into<String>) -> io::Result<PersistentMap<K, V>> {
+        let app_name = app_name.into();
+        let dir = collection_dir(&app_name, map_name.into())?;
+        Ok(PersistentMap { app_name, dir, marker: PhantomData })
+    }
+
+    /// Sets the value for a key, replacing any previous value atomically.
+    pub fn insert(&self, key: &K, value: &V) -> io::Result<()> {
+        let key = key.to_string();
+        let element = Element::of(&self.dir, &key)?;
+        let contents = element.contents(&key, &value.to_string());
+        crate::tracked_write(&self.app_name, &element.path, || crate::atomic_write(&element.path, contents.as_bytes()))
+    }
+
+    /// Returns the value for a key, or `None` if the key is not in the map.
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        match Element::of(&self.dir, &key.to_string())?.read()? {
+            Some(text) => Ok(Some(parse(&text)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key from the map. Returns `true` if it was present.
+    pub fn remove(&self, key: &K) -> io::Result<bool> {
+        let path = Element::of(&self.dir, &key.to_string())?.path;
+        match crate::tracked_write(&self.app_name, &path, || remove_file(&path)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns `true` if the map contains the key.
+    pub fn contains_key(&self, key: &K) -> io::Result<bool> {
+        Ok(Element::of(&self.dir, &key.to_string())?.path.is_file())
+    }
+
+    /// Returns an iterator over the keys in the map, sorted by their text form. Values
+    /// are not read.
+    pub fn keys(&self) -> io::Result<impl Iterator<Item = io::Result<K>>> {
+        Ok(element_names(&self.dir)?.into_iter().map(|name| parse(&name)))
+    }
+
+    /// Returns an iterator over the entries in the map, sorted by key. Each value is read
+    /// when its entry is reached; keys removed in the meantime are skipped.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<(K, V)>>> {
+        Ok(element_files(&self.dir)?.into_iter().filter_map(|(name, path)| {
+            let hashed = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(HASHED_PREFIX));
+            match (Element { path, hashed }).read() {
+                Ok(Some(text)) => Some(parse(&name).and_then(|key| Ok((key, parse(&text)?)))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(element_names(&self.dir)?.len())
+    }
+
+    /// Returns `true` if the map holds no entries.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod collections_test {
+    use super::*;
+
+    #[test]
+    fn test_encode_name_round_trip() {
+        for key in ["", "plain", "with space", "a/b", "../up", ".hidden", "%", "ünï"] {
+            let name = encode_name(key);
+            assert!(!name.starts_with('.'));
+            assert!(!name.contains('/'));
+            assert_eq!(decode_name(&name).as_deref(), Some(key));
+        }
+        assert_eq!(decode_name(".plain.tmp"), None);
+        assert_ne!(encode_name("Foo").to_lowercase(), encode_name("foo"));
+    }
+
+    #[test]
+    fn test_keys_differing_in_case_and_long_keys() {
+        let map: PersistentMap<String, String> = PersistentMap::open("prefstore_collections_test", "case").unwrap();
+        let long = "k".repeat(300);
+        for key in ["Foo", "foo", long.as_str()] {
+            map.remove(&key.to_string()).unwrap();
+        }
+        map.insert(&"Foo".to_string(), &"upper".to_string()).unwrap();
+        map.insert(&"foo".to_string(), &"lower".to_string()).unwrap();
+        map.insert(&long, &"line one\nline two".to_string()).unwrap();
+        assert_eq!(map.get(&"Foo".to_string()).unwrap().as_deref(), Some("upper"));
+        assert_eq!(map.get(&long).unwrap().as_deref(), Some("line one\nline two"));
+        let keys = map.keys().unwrap().collect::<io::Result<Vec<String>>>().unwrap();
+        assert_eq!(keys, vec!["Foo".to_string(), "foo".to_string(), long.clone()]);
+        assert!(map.iter().unwrap().any(|entry| entry.unwrap() == (long.clone(), "line one\nline two".to_string())));
+
+        let set: PersistentSet<String> = PersistentSet::open("prefstore_collections_test", "long_set").unwrap();
+        set.clear().unwrap();
+        assert!(set.insert(&long).unwrap());
+        assert!(set.contains(&long).unwrap());
+        assert_eq!(set.iter().unwrap().collect::<io::Result<Vec<String>>>().unwrap(), vec![long.clone()]);
+    }
+
+    #[test]
+    fn test_collection_names_are_validated() {
+        for name in ["", "../escape", "/etc"] {
+            assert_eq!(PersistentSet::<i32>::open("prefstore_collections_test", name).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(PersistentSet::<i32>::open("prefstore_collections_test", "nested/set").is_ok());
+    }
+
+    #[test]
+    fn test_set() {
+        let set: PersistentSet<i32> = PersistentSet::open("prefstore_collections_test", "set").unwrap();
+        set.clear().unwrap();
+        assert!(set.insert(&3).unwrap());
+        assert!(set.insert(&-1).unwrap());
+        assert!(!set.insert(&3).unwrap());
+        assert!(set.contains(&-1).unwrap());
+        assert!(set.remove(&-1).unwrap());
+        assert!(!set.remove(&-1).unwrap());
+        assert_eq!(set.iter().unwrap().collect::<io::Result<Vec<i32>>>().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_map() {
+        let map: PersistentMap<String, u32> = PersistentMap::open("prefstore_collections_test", "map").unwrap();
+        for key in map.keys().unwrap() {
+            map.remove(&key.unwrap()).unwrap();
+        }
+        map.insert(&"md".to_string(), &1).unwrap();
+        map.insert(&"rs".to_string(), &2).unwrap();
+        map.insert(&"md".to_string(), &3).unwrap();
+        assert_eq!(map.get(&"md".to_string()).unwrap(), Some(3));
+        assert_eq!(map.get(&"py".to_string()).unwrap(), None);
+        let entries = map.iter().unwrap().collect::<io::Result<Vec<(String, u32)>>>().unwrap();
+        assert_eq!(entries, vec![("md".to_string(), 3), ("rs".to_string(), 2)]);
+        assert_eq!(map.len().unwrap(), 2);
+    }
+}
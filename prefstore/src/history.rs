@@ -0,0 +1,323 @@
+//! The recent values of selected keys, to undo changes or go back to an earlier value.
+//!
+//! Keys an app tracks with [`track_history`] keep their last values with the time each was
+//! written, in a hidden file next to the value, `.{name}.prefstore-history`. Every write
+//! through the prefstore API adds an entry, including the removal of the key, so
+//! [`undo`] can bring back a cleared value. History is meant for text values such as
+//! preferences; other contents are kept with invalid UTF-8 replaced.
+//!
+//! Keys can be given as preference names, such as `"theme"`, or as paths relative to the
+//! app's folder, such as `"theme.txt"`, to every function here. No history is kept for
+//! keys marked with [`mark_sensitive`](crate::mark_sensitive), so their values are never
+//! copied to a history file.
+
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::encoding::{escape, unescape};
+
+const HISTORY_SUFFIX: &str = ".prefstore-history";
+
+/// The patterns an app tracks, each with the number of values to keep.
+type Tracked = HashMap<String, Vec<(glob::Pattern, usize)>>;
+
+static TRACKED: Mutex<Option<Tracked>> = Mutex::new(None);
+
+/// A value a key had.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// When the value was written, to the millisecond.
+    pub time: SystemTime,
+    /// The value, or `None` if the key did not exist.
+    pub value: Option<String>,
+}
+
+/// Keeps the last `keep` values, including the current one, of the app's keys matching a
+/// glob pattern. Keys are relative to the app's folder and matched with and without
+/// their extension, so `"theme"` tracks the preference `theme` and `"layouts/*"` every
+/// file in the `layouts` namespace.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{history, savepreference, track_history, undo};
+///
+/// track_history("myapp_history_doc", "theme", 10).unwrap();
+/// savepreference("myapp_history_doc", "theme", "dark").unwrap();
+/// savepreference("myapp_history_doc", "theme", "neon").unwrap();
+/// undo("myapp_history_doc", "theme.txt").unwrap();
+/// assert_eq!(prefstore::getpreference("myapp_history_doc", "theme", ""), "dark");
+/// ```
+///
+/// # Errors
+///
+/// Fails if the pattern is not a valid glob.
+pub fn track_history(app_name: impl Into<String>, pattern: &str, keep: usize) -> io::Result<()> {
+    let pattern = glob::Pattern::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    tracked.get_or_insert_with(HashMap::new).entry(app_name.into()).or_default().push((pattern, keep));
+    Ok(())
+}
+
+/// Returns the recorded values of a key, oldest first. The last entry is the current
+/// value. `key` is a preference name such as `"theme"` or a path relative to the app's
+/// folder such as `"theme.txt"`.
+///
+/// # Errors
+///
+/// Fails if the history cannot be read.
+pub fn history(app_name: impl Into<String>, key: &str) -> io::Result<Vec<HistoryEntry>> {
+    read_history(&history_path(&key_path(&app_name.into(), key)?)?)
+}
+
+/// Puts back the value a key had before its last change, and forgets the last change.
+/// Returns `false` if there is no earlier value.
+///
+/// # Errors
+///
+/// Fails if the value or its history cannot be written.
+pub fn undo(app_name: impl Into<String>, key: &str) -> io::Result<bool> {
+    let app_name = app_name.into();
+    let path = key_path(&app_name, key)?;
+    let history_path = history_path(&path)?;
+    let mut entries = read_history(&history_path)?;
+    if entries.len() < 2 {
+        return Ok(false);
+    }
+    entries.pop();
+    restore(&app_name, &path, entries.last().and_then(|entry| entry.value.as_deref()))?;
+    // Restoring added the value again as a new change, replace it with the shortened history.
+    write_history(&history_path, &entries)?;
+    Ok(true)
+}
+
+/// Puts back the value a key had at `time`, as a new change that [`undo`] can take back.
+///
+/// # Errors
+///
+/// Fails with `NotFound` if the history starts after `time`, or if the value cannot be
+/// written.
+pub fn revert_to(app_name: impl Into<String>, key: &str, time: SystemTime) -> io::Result<()> {
+    let app_name = app_name.into();
+    let path = key_path(&app_name, key)?;
+    let entries = read_history(&history_path(&path)?)?;
+    let entry = entries.iter().rev().find(|entry| entry.time <= time)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no value of {} recorded at that time", key)))?;
+    restore(&app_name, &path, entry.value.as_deref())
+}
+
+pub(crate) fn is_enabled(app_name: &str) -> bool {
+    TRACKED.lock().unwrap_or_else(|e| e.into_inner()).as_ref().is_some_and(|tracked| tracked.contains_key(app_name))
+}
+
+/// What a file held before a write, read by `tracked_write` when the app tracks history.
+pub(crate) struct Before {
+    value: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+impl Before {
+    pub(crate) fn read(path: &Path) -> Before {
+        Before {
+            value: crate::read_reported_value(path),
+            modified: fs::metadata(path).and_then(|metadata| metadata.modified()).ok(),
+        }
+    }
+}
+
+/// Adds the change of the file at `path` from `before` to `new` to its history, if the
+/// app tracks it and the key is not sensitive.
+pub(crate) fn record(app_name: &str, path: &Path, before: Before, new: Option<&str>) -> io::Result<()> {
+    if before.value.as_deref() == new {
+        return Ok(());
+    }
+    let key = crate::relative_key(&crate::config_folder_path(app_name)?, path)?;
+    if crate::is_sensitive(app_name, &key) {
+        return Ok(());
+    }
+    let keep = match keep_of(app_name, &key) {
+        Some(keep) => keep,
+        None => return Ok(()),
+    };
+    let history_path = history_path(path)?;
+    let mut entries = read_history(&history_path)?;
+    if entries.is_empty() {
+        // The value from before tracking started, so the first change can be undone too.
+        let time = match before.value {
+            Some(_) => before.modified.unwrap_or_else(SystemTime::now),
+            None => SystemTime::now(),
+        };
+        entries.push(HistoryEntry { time, value: before.value });
+    }
+    entries.push(HistoryEntry { time: SystemTime::now(), value: new.map(str::to_string) });
+    let excess = entries.len().saturating_sub(keep);
+    entries.drain(..excess);
+    write_history(&history_path, &entries)
+}
+
+fn keep_of(app_name: &str, key: &str) -> Option<usize> {
+    let tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    tracked.as_ref()?.get(app_name)?.iter().rev().find(|(pattern, _)| crate::key_matches(pattern, key)).map(|(_, keep)| *keep)
+}
+
+/// The file of `key`: the file it names if that exists or has a history, otherwise the
+/// file of the preference `key` if it has no extension.
+fn key_path(app_name: &str, key: &str) -> io::Result<PathBuf> {
+    let path = crate::customfile_path(&app_name.to_string(), key)?;
+    if Path::new(key).extension().is_some() || path.exists() || history_path(&path)?.exists() {
+        return Ok(path);
+    }
+    crate::customfile_path(&app_name.to_string(), format!("{}.txt", key))
+}
+
+fn history_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+        .to_string_lossy();
+    Ok(path.with_file_name(format!(".{}{}", name, HISTORY_SUFFIX)))
+}
+
+/// Writes `value` to the file at `path`, or removes it for `None`, as any other change.
+fn restore(app_name: &str, path: &Path, value: Option<&str>) -> io::Result<()> {
+    match value {
+        Some(value) => {
+            if let Some(parent) = path.parent() {
+                crate::permissions::create_dirs(parent)?;
+            }
+            crate::tracked_write(app_name, path, || crate::atomic_write(path, value.as_bytes()))
+        },
+        None => match crate::tracked_write(app_name, path, || fs::remove_file(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+// Each line holds the milliseconds since the Unix epoch, a tab, and `-` for a missing
+// value or `=` followed by the escaped value.
+
+fn read_history(history_path: &Path) -> io::Result<Vec<HistoryEntry>> {
+    let contents = match fs::read_to_string(history_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    contents.lines().map(|line| parse_line(line)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed history entry in {}", history_path.display()))))
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<HistoryEntry> {
+    let (millis, value) = line.split_once('\t')?;
+    let value = match value.split_at_checked(1)? {
+        ("-", "") => None,
+        ("=", value) => Some(unescape(value)?),
+        _ => return None,
+    };
+    Some(HistoryEntry { time: UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?), value })
+}
+
+fn write_history(history_path: &Path, entries: &[HistoryEntry]) -> io::Result<()> {
+    let mut contents = String::new();
+    for entry in entries {
+        let millis = entry.time.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        match &entry.value {
+            Some(value) => contents.push_str(&format!("{}\t={}\n", millis, escape(value))),
+            None => contents.push_str(&format!("{}\t-\n", millis)),
+        }
+    }
+    crate::atomic_write(history_path, contents.as_bytes())
+}
+
+#[cfg(test)]
+mod history_test {
+    use super::*;
+    use crate::*;
+
+    fn values(app_name: &str, key: &str) -> Vec<Option<String>> {
+        history(app_name, key).unwrap().into_iter().map(|entry| entry.value).collect()
+    }
+
+    #[test]
+    fn test_undo_and_revert() {
+        let app_name = "prefstore_history_test";
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        track_history(app_name, "theme", 3).unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        assert_eq!(values(app_name, "theme.txt"), vec![None, Some("dark".to_string()), Some("light".to_string())]);
+        std::thread::sleep(Duration::from_millis(5));
+        let before_blue = SystemTime::now();
+        savepreference(app_name, "theme", "blue").unwrap();
+        // Only the last 3 values are kept.
+        assert_eq!(values(app_name, "theme.txt"), vec![Some("dark".to_string()), Some("light".to_string()), Some("blue".to_string())]);
+
+        assert!(undo(app_name, "theme.txt").unwrap());
+        assert_eq!(getpreference(app_name, "theme", ""), "light");
+        assert!(undo(app_name, "theme.txt").unwrap());
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+        assert!(!undo(app_name, "theme.txt").unwrap());
+
+        savepreference(app_name, "theme", "blue").unwrap();
+        clearpreference(app_name, "theme").unwrap();
+        assert!(undo(app_name, "theme.txt").unwrap());
+        assert_eq!(getpreference(app_name, "theme", ""), "blue");
+
+        let first = history(app_name, "theme.txt").unwrap()[0].time;
+        revert_to(app_name, "theme.txt", before_blue).unwrap();
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+        assert_eq!(values(app_name, "theme.txt").last().unwrap().as_deref(), Some("dark"));
+        let long_ago = first - Duration::from_secs(60);
+        assert_eq!(revert_to(app_name, "theme.txt", long_ago).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // Preference names work as well as file names.
+        assert_eq!(history(app_name, "theme").unwrap(), history(app_name, "theme.txt").unwrap());
+        savepreference(app_name, "theme", "green").unwrap();
+        assert!(undo(app_name, "theme").unwrap());
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+
+        // Untracked keys and the history files themselves stay out of the way.
+        savepreference(app_name, "volume", 3).unwrap();
+        assert!(history(app_name, "volume.txt").unwrap().is_empty());
+        assert_eq!(getall(app_name).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_custom_files_written_before_tracking() {
+        let app_name = "prefstore_history_custom_test";
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        savecustom(app_name, "layouts/main.json", "{\"columns\": 2}").unwrap();
+        track_history(app_name, "layouts/*", 10).unwrap();
+        savecustom(app_name, "layouts/main.json", "{\"columns\":\n 3}").unwrap();
+        assert!(undo(app_name, "layouts/main.json").unwrap());
+        assert_eq!(getcustom(app_name, "layouts/main.json", "").unwrap(), "{\"columns\": 2}");
+        assert_eq!(listcustomkeys(app_name, "layouts", &["json"], Depth::Recursive).unwrap(), vec!["layouts/main.json"]);
+    }
+
+    #[test]
+    fn test_first_entry_keeps_the_time_of_the_old_value() {
+        let app_name = "prefstore_history_time_test";
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        savepreference(app_name, "theme", "dark").unwrap();
+        let written = fs::metadata(config_folder_path(app_name).unwrap().join("theme.txt")).unwrap().modified().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        track_history(app_name, "theme", 10).unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        let entries = history(app_name, "theme").unwrap();
+        let millis = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(millis(entries[0].time), millis(written));
+        assert!(entries[1].time > entries[0].time);
+    }
+
+    #[test]
+    fn test_sensitive_keys_have_no_history() {
+        let app_name = "prefstore_history_sensitive_test";
+        let _ = fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        track_history(app_name, "*", 10).unwrap();
+        mark_sensitive(app_name, "token").unwrap();
+        savepreference(app_name, "token", "s3cr3t").unwrap();
+        savepreference(app_name, "token", "n3w").unwrap();
+        assert!(history(app_name, "token").unwrap().is_empty());
+        assert!(!config_folder_path(app_name).unwrap().join(".token.txt.prefstore-history").exists());
+    }
+}
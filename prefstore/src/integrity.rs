@@ -0,0 +1,309 @@
+//! Checksums or keyed HMACs of stored values, available with the `integrity` feature.
+//!
+//! Once an app has an integrity mode, every value written through the prefstore API gets
+//! a record in a hidden file next to it, `.{name}.prefstore-sum`, holding a SHA-256
+//! checksum of the value or an HMAC-SHA256 of the key and value. Reads through the API
+//! check the value against its record and fail with an [`IntegrityError`] when they do
+//! not match, and [`verify_all`] checks every value at once.
+//!
+//! Values without a record, such as files written before the mode was set, are not
+//! checked; [`seal_all`] records them. A checksum catches accidental damage such as a
+//! partial write, while an HMAC also catches deliberate edits by anyone without the key.
+
+use std::{collections::HashMap, error::Error, fmt, fs, io, path::{Path, PathBuf}, sync::Mutex};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+const RECORD_SUFFIX: &str = ".prefstore-sum";
+
+static MODES: Mutex<Option<HashMap<PathBuf, Integrity>>> = Mutex::new(None);
+
+/// How the values of an app are protected.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Integrity {
+    /// A SHA-256 checksum of each value, which detects corruption.
+    Checksum,
+    /// An HMAC-SHA256 of each key and value with a secret key, which also detects edits
+    /// made without the key, and values moved to another key.
+    Hmac(Vec<u8>),
+}
+
+impl fmt::Debug for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Integrity::Checksum => f.write_str("Checksum"),
+            Integrity::Hmac(_) => f.write_str("Hmac(..)"),
+        }
+    }
+}
+
+/// What is wrong with a value that failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityErrorKind {
+    /// The value does not match its checksum, or its record is unreadable.
+    Corrupted,
+    /// The value does not match its HMAC: it was changed without the key.
+    Tampered,
+}
+
+/// A value that failed verification. Reads report it as an `io::Error` of kind
+/// `InvalidData`; use [`IntegrityError::of`] to get it back.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{getcustom, IntegrityError};
+///
+/// match getcustom("myapp", "license.txt", "") {
+///     Err(e) if IntegrityError::of(&e).is_some() => eprintln!("license file was modified"),
+///     _ => {},
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityError {
+    /// The key of the value, relative to the app's folder, such as `"license.txt"`.
+    pub key: String,
+    /// What is wrong with it.
+    pub kind: IntegrityErrorKind,
+}
+
+impl IntegrityError {
+    /// The integrity error carried by an `io::Error`, if it is one.
+    pub fn of(error: &io::Error) -> Option<&IntegrityError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            IntegrityErrorKind::Corrupted => write!(f, "{} is corrupted: it does not match its checksum", self.key),
+            IntegrityErrorKind::Tampered => write!(f, "{} was modified outside the application", self.key),
+        }
+    }
+}
+
+impl Error for IntegrityError {}
+
+impl From<IntegrityError> for io::Error {
+    fn from(error: IntegrityError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Protects the values of an app written from now on, replacing any previous mode.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{set_integrity, Integrity};
+///
+/// set_integrity("myapp", Integrity::Hmac(b"a key only the app knows".to_vec())).unwrap();
+/// ```
+pub fn set_integrity(app_name: impl Into<String>, integrity: Integrity) -> io::Result<()> {
+    let dir = crate::config_folder_path(&app_name.into())?;
+    MODES.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).insert(dir, integrity);
+    Ok(())
+}
+
+/// Checks every value of the app that has a record, returning the ones that fail, in key
+/// order. Values without a record are skipped.
+///
+/// # Errors
+///
+/// Fails if the app has no integrity mode, or a value cannot be read.
+pub fn verify_all(app_name: impl Into<String>) -> io::Result<Vec<IntegrityError>> {
+    let app_name = app_name.into();
+    let (app_dir, integrity) = mode_of_app(&app_name)?;
+    let mut failed = Vec::new();
+    for info in crate::list_keys(&app_name, "")? {
+        if let Err(error) = check(&app_dir, &integrity, &info.path, &fs::read(&info.path)?) {
+            failed.push(error);
+        }
+    }
+    Ok(failed)
+}
+
+/// Records every value of the app as it is now, including values written before the
+/// integrity mode was set or under another mode. Returns how many values were recorded.
+///
+/// # Errors
+///
+/// Fails if the app has no integrity mode, or a value cannot be read or recorded.
+pub fn seal_all(app_name: impl Into<String>) -> io::Result<usize> {
+    let app_name = app_name.into();
+    let (app_dir, integrity) = mode_of_app(&app_name)?;
+    let keys = crate::list_keys(&app_name, "")?;
+    for info in &keys {
+        write_record(&app_dir, &integrity, &info.path)?;
+    }
+    Ok(keys.len())
+}
+
+fn mode_of_app(app_name: &str) -> io::Result<(PathBuf, Integrity)> {
+    let app_dir = crate::config_folder_path(app_name)?;
+    match mode_of(&app_dir) {
+        Some((_, integrity)) => Ok((app_dir, integrity)),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no integrity mode", app_name))),
+    }
+}
+
+/// The app folder containing `path` and its integrity mode, if it has one.
+fn mode_of(path: &Path) -> Option<(PathBuf, Integrity)> {
+    let modes = MODES.lock().unwrap_or_else(|e| e.into_inner());
+    modes.as_ref()?.iter().find(|(dir, _)| path.starts_with(dir)).map(|(dir, integrity)| (dir.clone(), integrity.clone()))
+}
+
+fn record_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    Some(path.with_file_name(format!(".{}{}", name, RECORD_SUFFIX)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The record of `contents` stored at `key`.
+fn record(integrity: &Integrity, key: &str, contents: &[u8]) -> String {
+    match integrity {
+        Integrity::Checksum => format!("sha256 {}\n", to_hex(&Sha256::digest(contents))),
+        Integrity::Hmac(secret) => {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+            mac.update(key.as_bytes());
+            mac.update(b"\0");
+            mac.update(contents);
+            format!("hmac-sha256 {}\n", to_hex(&mac.finalize().into_bytes()))
+        },
+    }
+}
+
+fn write_record(app_dir: &Path, integrity: &Integrity, path: &Path) -> io::Result<()> {
+    let record_path = record_path(path).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    match fs::read(path) {
+        Ok(contents) => {
+            let key = crate::relative_key(app_dir, path)?;
+            crate::atomic_write(&record_path, record(integrity, &key, &contents).as_bytes())
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::remove_file(&record_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn check(app_dir: &Path, integrity: &Integrity, path: &Path, contents: &[u8]) -> Result<(), IntegrityError> {
+    let key = crate::relative_key(app_dir, path).unwrap_or_else(|_| path.display().to_string());
+    let stored = match record_path(path).map(fs::read_to_string) {
+        Some(Ok(stored)) => stored,
+        Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        _ => return Err(IntegrityError { key, kind: IntegrityErrorKind::Corrupted }),
+    };
+    if stored == record(integrity, &key, contents) {
+        return Ok(());
+    }
+    let kind = match integrity {
+        Integrity::Checksum => IntegrityErrorKind::Corrupted,
+        Integrity::Hmac(_) => IntegrityErrorKind::Tampered,
+    };
+    Err(IntegrityError { key, kind })
+}
+
+/// Updates the record of the file at `path` after it was written or removed.
+pub(crate) fn after_write(path: &Path) -> io::Result<()> {
+    match mode_of(path) {
+        Some((app_dir, integrity)) => write_record(&app_dir, &integrity, path),
+        None => Ok(()),
+    }
+}
+
+/// Checks `contents`, just read from `path`, against its record.
+pub(crate) fn verify(path: &Path, contents: &[u8]) -> io::Result<()> {
+    match mode_of(path) {
+        Some((app_dir, integrity)) => Ok(check(&app_dir, &integrity, path, contents)?),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod integrity_test {
+    use super::*;
+    use crate::{getcustom, getpreference, savecustom, savepreference, Store};
+
+    fn fresh(app_name: &str, integrity: Integrity) -> PathBuf {
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        set_integrity(app_name, integrity).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let app_name = "prefstore_integrity_checksum_test";
+        let dir = fresh(app_name, Integrity::Checksum);
+        savepreference(app_name, "theme", "dark").unwrap();
+        savecustom(app_name, "logs/today.log", "started").unwrap();
+        assert_eq!(getcustom(app_name, "theme.txt", "").unwrap(), "dark");
+        assert!(verify_all(app_name).unwrap().is_empty());
+
+        fs::write(dir.join("logs/today.log"), "star").unwrap();
+        let error = getcustom(app_name, "logs/today.log", "").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(IntegrityError::of(&error), Some(&IntegrityError { key: "logs/today.log".to_string(), kind: IntegrityErrorKind::Corrupted }));
+        let entry = Store::new(app_name).unwrap().iter("logs").unwrap().next().unwrap();
+        assert!(entry.is_err());
+        let error = crate::getallcustomkeyed(app_name, "logs", &["log"], crate::Depth::Recursive).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // A read that fails verification falls back to the default.
+        fs::write(dir.join("theme.txt"), "light").unwrap();
+        assert_eq!(getpreference(app_name, "theme", "blue"), "blue");
+        let keys: Vec<String> = verify_all(app_name).unwrap().into_iter().map(|error| error.key).collect();
+        assert_eq!(keys, vec!["logs/today.log", "theme.txt"]);
+
+        // Writing through the API, or sealing, records the new values.
+        savecustom(app_name, "logs/today.log", "started again").unwrap();
+        assert_eq!(seal_all(app_name).unwrap(), 2);
+        assert!(verify_all(app_name).unwrap().is_empty());
+
+        crate::clearpreference(app_name, "theme").unwrap();
+        assert!(!dir.join(".theme.txt.prefstore-sum").exists());
+    }
+
+    #[test]
+    fn test_old_names_are_verified() {
+        let app_name = "prefstore_integrity_alias_test";
+        let dir = fresh(app_name, Integrity::Checksum);
+        savepreference(app_name, "dark_mode", "dark").unwrap();
+        crate::register_alias(app_name, crate::Alias::new("dark_mode", "theme")).unwrap();
+        assert_eq!(getpreference(app_name, "theme", "light"), "dark");
+
+        fs::write(dir.join("dark_mode.txt"), "neon").unwrap();
+        assert_eq!(getpreference(app_name, "theme", "light"), "light");
+        let error = crate::getpreferencenodefault(app_name, "theme").unwrap_err();
+        assert_eq!(IntegrityError::of(&error).unwrap().kind, IntegrityErrorKind::Corrupted);
+    }
+
+    #[test]
+    fn test_hmac_detects_tampering() {
+        let app_name = "prefstore_integrity_hmac_test";
+        let dir = fresh(app_name, Integrity::Hmac(b"kiosk key".to_vec()));
+        savepreference(app_name, "licensed", "false").unwrap();
+        savepreference(app_name, "kiosk", "true").unwrap();
+        assert_eq!(getcustom(app_name, "licensed.txt", "").unwrap(), "false");
+
+        // Recomputing a plain checksum does not help without the key.
+        fs::write(dir.join("licensed.txt"), "true").unwrap();
+        let checksum = record(&Integrity::Checksum, "licensed.txt", b"true");
+        fs::write(dir.join(".licensed.txt.prefstore-sum"), checksum).unwrap();
+        let error = getcustom(app_name, "licensed.txt", "").unwrap_err();
+        assert_eq!(IntegrityError::of(&error).unwrap().kind, IntegrityErrorKind::Tampered);
+
+        // Nor does copying another key's value along with its record.
+        fs::copy(dir.join("kiosk.txt"), dir.join("licensed.txt")).unwrap();
+        fs::copy(dir.join(".kiosk.txt.prefstore-sum"), dir.join(".licensed.txt.prefstore-sum")).unwrap();
+        let failed = verify_all(app_name).unwrap();
+        assert_eq!(failed, vec![IntegrityError { key: "licensed.txt".to_string(), kind: IntegrityErrorKind::Tampered }]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Typed key constants, read and written through a [`Store`].
+
+use std::{fmt::Display, io, str::FromStr};
+
+use crate::Store;
+
+/// The name of a preference together with its type and default value.
+///
+/// Declaring keys once as constants turns a misspelt key or a value of the wrong type
+/// into a compile error, where `savepreference` would silently create a new file. The
+/// value is stored as text: `T` is written with `ToString` and read back with `FromStr`,
+/// so it is stored exactly like the same value passed to `savepreference`. Text keys are
+/// `Key<String>`, which cannot be a constant with a non-empty default; an enum that
+/// implements `FromStr` and `Display` can.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{Key, Store};
+///
+/// const FONT_SIZE: Key<i32> = Key::new("font_size", 12);
+///
+/// let store = Store::new("myapp_key_doc").unwrap();
+/// store.set(FONT_SIZE, 14).unwrap();
+/// let size: i32 = store.get(FONT_SIZE).unwrap();
+/// assert_eq!(size, 14);
+/// assert_eq!(FONT_SIZE.name(), "font_size");
+///
+/// let theme = Key::new("theme", "light".to_string());
+/// store.remove(theme.clone()).unwrap();
+/// assert_eq!(store.get(theme).unwrap(), "light");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key<T> {
+    name: &'static str,
+    default: T,
+}
+
+impl<T> Key<T> {
+    /// Declares a key stored as the preference `name`, which may include a namespace such
+    /// as `"window/width"`, reading as `default` while it is not stored.
+    pub const fn new(name: &'static str, default: T) -> Key<T> {
+        Key { name, default }
+    }
+
+    /// The name of the preference.
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The value read while the preference is not stored.
+    pub const fn default_value(&self) -> &T {
+        &self.default
+    }
+}
+
+impl Store {
+    /// Reads a typed key, returning its default if it is not stored.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the stored value cannot be read, or cannot be parsed as a `T`. The error
+    /// then has the kind `InvalidData`.
+    pub fn get<T: FromStr>(&self, key: Key<T>) -> io::Result<T>
+    where T::Err: Display {
+        crate::preferences::read_typed(self.app_name(), key.name, || Ok(key.default))
+    }
+
+    /// Stores a value for a typed key.
+    pub fn set<T: ToString>(&self, key: Key<T>, value: T) -> io::Result<()> {
+        crate::savepreference(self.app_name(), key.name, value)
+    }
+
+    /// Removes a typed key, so that it reads as its default again. Removing a key that is
+    /// not stored does nothing.
+    pub fn remove<T>(&self, key: Key<T>) -> io::Result<()> {
+        match crate::clearpreference(self.app_name(), key.name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod key_test {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Theme {
+        Light,
+        Dark,
+    }
+
+    impl Display for Theme {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                Theme::Light => "light",
+                Theme::Dark => "dark",
+            })
+        }
+    }
+
+    impl FromStr for Theme {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Theme, String> {
+            match s {
+                "light" => Ok(Theme::Light),
+                "dark" => Ok(Theme::Dark),
+                _ => Err(format!("unknown theme {:?}", s)),
+            }
+        }
+    }
+
+    const THEME: Key<Theme> = Key::new("theme", Theme::Light);
+    const WIDTH: Key<u32> = Key::new("window/width", 800);
+
+    #[test]
+    fn test_typed_keys() {
+        let app_name = "prefstore_key_test";
+        let store = Store::new(app_name).unwrap();
+        let _ = std::fs::remove_dir_all(store.dir());
+
+        assert_eq!(store.get(THEME).unwrap(), Theme::Light);
+        store.set(THEME, Theme::Dark).unwrap();
+        assert_eq!(store.get(THEME).unwrap(), Theme::Dark);
+        assert_eq!(crate::getpreference(app_name, "theme", ""), "dark");
+
+        store.set(WIDTH, 1024).unwrap();
+        assert_eq!(crate::getpreference(app_name, "window/width", 0), "1024");
+        assert_eq!(store.get(WIDTH).unwrap(), 1024);
+        store.remove(WIDTH).unwrap();
+        store.remove(WIDTH).unwrap();
+        assert_eq!(store.get(WIDTH).unwrap(), 800);
+
+        crate::savepreference(app_name, "theme", "sepia").unwrap();
+        assert_eq!(store.get(THEME).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
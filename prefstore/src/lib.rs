@@ -1,9 +1,59 @@
-#![allow(warnings)] 
 
-use std::{fs::{File, create_dir_all, remove_file, read_to_string,OpenOptions}, io::{Write,BufReader, self, Read, Error, BufRead}, path::{PathBuf, Path}, collections::HashMap, fmt::format};
-use dirs;
+use std::{fs::{File, create_dir_all, remove_file, OpenOptions}, io::{Write,BufReader, self, Read, BufRead}, path::{PathBuf, Path}, collections::{BTreeMap, HashMap}, time::SystemTime};
 // use url::form_urlencoded;
-use std::env::var;
+
+mod alias;
+mod audit;
+mod buffer;
+mod bundle;
+mod collections;
+mod encoding;
+mod history;
+#[cfg(feature = "integrity")]
+mod integrity;
+mod key;
+mod live;
+mod migrate;
+mod observe;
+mod permissions;
+mod preferences;
+mod queue;
+mod schema;
+mod search;
+#[cfg(feature = "encryption")]
+mod secret;
+mod sensitive;
+mod snapshot;
+mod store;
+mod watch;
+
+pub use alias::{on_deprecation, register_alias, Alias, Deprecation, DeprecationKind};
+pub use audit::{read_audit, rotate_audit, set_audit, stop_audit, Audit, AuditEntry, AuditQuery, AuditValue, AuditValues};
+pub use bundle::{export, export_with, import, import_with, ExportFormat, ExportOptions, ImportMode, ImportOptions, ImportReport};
+pub use collections::{PersistentMap, PersistentSet};
+pub use history::{history, revert_to, track_history, undo, HistoryEntry};
+#[cfg(feature = "integrity")]
+pub use integrity::{seal_all, set_integrity, verify_all, Integrity, IntegrityError, IntegrityErrorKind};
+pub use key::Key;
+pub use live::{FromPreferences, Live};
+pub use migrate::{Migration, Migrations};
+pub use observe::{observe, observe_channel, Subscription, WriteEvent};
+pub use permissions::{check_permissions, set_file_modes, FileModes, PermissionWarning};
+pub use preferences::Preferences;
+#[doc(hidden)]
+pub use preferences::__private;
+#[cfg(feature = "derive")]
+pub use prefstore_derive::Preferences;
+pub use queue::{Lease, Queue};
+pub use schema::{getschemapreference, getschemavalue, register_schema, schema_for, PrefSpec, PrefType, PrefValue, Schema};
+pub use search::{search, SearchMatch, SearchPattern};
+#[cfg(feature = "encryption")]
+pub use secret::{clear_secret, get_secret, get_secret_bytes, reencrypt_secrets, save_secret, KeyFile, KeyFn, KeyProvider, KeyRing, Passphrase, SecretKey};
+pub use sensitive::{is_sensitive, mark_sensitive, ReadOptions, REDACTED};
+pub use snapshot::{list_snapshots, set_snapshot_retention, snapshot, Retention, Snapshot};
+pub use store::{Entries, Store};
+pub use watch::{watch, watch_with, Change, ChangeKind, WatchOptions, Watcher};
+
 const MSG_NO_SYSTEM_CONFIG_DIR: &str = "no system config directory detected";
 
 // #[no_mangle]
@@ -25,9 +75,13 @@ const MSG_NO_SYSTEM_CONFIG_DIR: &str = "no system config directory detected";
 ///
 /// # Errors
 ///
-/// This function will return an error if it is unable to create the necessary directories or file.
+/// This function will return an error if it is unable to create the necessary directories or file,
+/// or if the app's schema declares the key and does not accept the value.
 pub fn savepreference<T: ToString>(app_name:impl Into<String>,key: impl Into<String>,value:T) -> std::io::Result<()> {
-    savecustom(app_name, format!("{}.txt",key.into()), value)
+    let app_name = app_name.into();
+    let key = alias::resolve(&app_name, &key.into());
+    savecustom(app_name.as_str(), format!("{}.txt",key), value)?;
+    alias::remove_old(&app_name, &key, false).map(|_| ())
 }
 /// Save custom data to a file with the given app name, filename, and value.
 ///
@@ -35,6 +89,9 @@ pub fn savepreference<T: ToString>(app_name:impl Into<String>,key: impl Into<Str
 /// path defined by the `customfile_path` function. The `create_dir_all` function is called
 /// to ensure that all the necessary directories are created for the specified path.
 ///
+/// The value is written to a hidden temporary file that is then renamed over the target,
+/// so readers and watchers never see a partially written value.
+///
 /// # Arguments
 ///
 /// * `app_name` - A parameter of type `impl Into<String>`, which means it can accept any type that can be converted into a `String`.
@@ -44,6 +101,8 @@ pub fn savepreference<T: ToString>(app_name:impl Into<String>,key: impl Into<Str
 /// # Examples
 ///
 /// ```rust
+/// use prefstore::savecustom;
+///
 /// savecustom("my_app", "my_file.txt", "Hello, world!");
 /// ```
 ///
@@ -60,16 +119,18 @@ pub fn savecustom<T: ToString>(app_name:impl Into<String>,custom_filename_with_e
     let parent_path = path.parent()
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find path to {fname}")))?;
 
-    create_dir_all(parent_path)?;
+    let value = value.to_string();
+    if let Some(spec) = schema::spec_for_file(&app_name, &key) {
+        spec.parse(&value)?;
+    }
 
-    let mut file = File::create(&path)?;
-    write!(file, "{}", value.to_string())?;
+    permissions::create_dirs(parent_path)?;
 
-    Ok(())
+    tracked_write(&app_name, &path, || atomic_write(&path, value.as_bytes()))
 }
 #[test]
 fn uiouy(){
-    save_else_where("/tmp/new/try.json", "value");
+    save_else_where("/tmp/new/try.json", "value").unwrap();
 }
 pub fn save_else_where<T: ToString>(custom_filename_with_extension: impl Into<String>,value:T) -> std::io::Result<()> {
     let key=custom_filename_with_extension.into();
@@ -81,7 +142,7 @@ pub fn save_else_where<T: ToString>(custom_filename_with_extension: impl Into<St
 
     create_dir_all(parent_path)?;
 
-    let mut file = File::create(&path)?;
+    let mut file = File::create(path)?;
     write!(file, "{}", value.to_string())?;
 
     Ok(())
@@ -107,17 +168,23 @@ pub fn initcustomfile<T: ToString>(app_name: impl Into<String>, custom_filename_
     let parent_path = path.parent()
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find path to {}", fname)))?;
 
-    create_dir_all(parent_path)?;
+    let value = value.to_string();
+    if let Some(spec) = schema::spec_for_file(&app_name, &key) {
+        spec.parse(&value)?;
+    }
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&path)?;
-    file.write_all(format!("{}", value.to_string()).as_bytes())?;
-    Ok(())
+    permissions::create_dirs(parent_path)?;
+
+    tracked_write(&app_name, &path, || {
+        let mut file = permissions::open(&path, OpenOptions::new()
+            .write(true)
+            .create_new(true))?;
+        file.write_all(value.as_bytes())
+    })
 }
 
-/// Appends a value to a custom file for the given application.
+/// Appends a value to a custom file for the given application. If the file stores a
+/// preference declared in the app's schema, the appended result must fit the declaration.
 ///
 /// # Arguments
 ///
@@ -128,13 +195,10 @@ pub fn initcustomfile<T: ToString>(app_name: impl Into<String>, custom_filename_
 /// # Example
 ///
 /// ```rust
-/// Appends the given value to the file with the given app name and key.
-///
-/// # Arguments
+/// use prefstore::appendcustom;
 ///
-/// * `app_name`: The name of the app.
-/// * `custom_filename_with_extension`: The custom filename with extension.
-/// * `value`: The value to append.
+/// appendcustom("my_app", "hobbies.txt", "reading");
+/// ```
 pub fn appendcustom<T: ToString>(app_name: impl Into<String>, custom_filename_with_extension: impl Into<String>, value: T) -> std::io::Result<()> {
     let key = custom_filename_with_extension.into();
     let fname = "#appendcustom";
@@ -144,14 +208,18 @@ pub fn appendcustom<T: ToString>(app_name: impl Into<String>, custom_filename_wi
     let parent_path = path.parent()
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find path to {}", fname)))?;
 
-    create_dir_all(parent_path)?;
+    permissions::create_dirs(parent_path)?;
 
-    let mut file = File::options().create(true).append(true).open(&path)?;
-    write!(file, "{}", value.to_string())?;
-    Ok(())
+    let value = value.to_string();
+    check_appended(&app_name, &key, &path, &value)?;
+    tracked_write(&app_name, &path, || {
+        let mut file = permissions::open(&path, File::options().create(true).append(true))?;
+        write!(file, "{}", value)
+    })
 }
 
 /// Appends the given value to the file with the given app name and key, followed by a newline character.
+/// Like `appendcustom`, the result must fit the app's schema if the file stores a declared preference.
 ///
 /// # Arguments
 ///
@@ -167,11 +235,26 @@ pub fn appendcustomnewline<T: ToString>(app_name: impl Into<String>, custom_file
     let parent_path = path.parent()
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find path to {}", fname)))?;
 
-    create_dir_all(parent_path)?;
+    permissions::create_dirs(parent_path)?;
 
-    let mut file = File::options().create(true).append(true).open(&path)?;
-    writeln!(file, "{}", value.to_string())?;
-    Ok(())
+    let value = value.to_string();
+    check_appended(&app_name, &key, &path, &format!("{}\n", value))?;
+    tracked_write(&app_name, &path, || {
+        let mut file = permissions::open(&path, File::options().create(true).append(true))?;
+        writeln!(file, "{}", value)
+    })
+}
+
+/// Checks that appending `appended` to the file at `path` leaves a value that fits the
+/// schema, if the file stores a declared preference.
+fn check_appended(app_name: &str, key: &str, path: &Path, appended: &str) -> std::io::Result<()> {
+    let Some(spec) = schema::spec_for_file(app_name, key) else { return Ok(()) };
+    let current = match std::fs::read(path) {
+        Ok(current) => String::from_utf8_lossy(&current).into_owned(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    spec.parse(&format!("{}{}", current, appended)).map(|_| ())
 }
 
 
@@ -183,7 +266,7 @@ pub fn appendcustomnewline<T: ToString>(app_name: impl Into<String>, custom_file
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// use prefstore::default_name;
 ///
 /// let filename = "mykey";
@@ -194,7 +277,7 @@ fn default_name(filename:String) -> String {
     custom_file_name(format!("{}.txt", filename))
 }
 fn custom_file_name(filename:String) -> String {
-    format!("{}", filename)
+    filename.to_string()
 }
 
 /// Returns the path to the configuration file for the given app_name and filename.
@@ -210,7 +293,7 @@ fn custom_file_name(filename:String) -> String {
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// use prefstore::config_path;
 ///
 /// let app_name = "myapp";
@@ -218,7 +301,7 @@ fn custom_file_name(filename:String) -> String {
 /// let path = config_path(&app_name.to_string(), &filename.to_string()).unwrap();
 /// ```
 fn config_path(app_name:&String,filename:impl Into<String>) -> std::io::Result<PathBuf> {
-    match(dirs::config_dir()){
+    match dirs::config_dir(){
         Some(system_config_dir) =>{
             Ok(system_config_dir
                     .join(app_name)
@@ -242,7 +325,7 @@ fn config_path(app_name:&String,filename:impl Into<String>) -> std::io::Result<P
 /// # Example
 ///
 /// ```
-/// use myapp::prefstore_directory;
+/// use prefstore::prefstore_directory;
 /// let app_name = String::from("myapp");
 /// let directory = prefstore_directory(&app_name);
 /// ```
@@ -260,14 +343,14 @@ pub fn prefstore_directory(app_name:&String)->Result<PathBuf,std::io::Error>{
 ///
 /// * `app_name` - A reference to a `String` containing the name of the application.
 /// * `filename` - A value that can be converted into a `String` containing the name of
-///                the custom file.
+///   the custom file.
 ///
 /// # Returns
 ///
 /// A `Result` containing the full path to the custom file or an IO error if the system config directory cannot be found.
 fn customfile_path(app_name:&String,filename:impl Into<String>) -> std::io::Result<PathBuf> {
     // Get the system configuration directory.
-    match(dirs::config_dir()){
+    match dirs::config_dir(){
         Some(system_config_dir) =>{
             // Join the system configuration directory, the app name, and the custom file name.
             Ok(system_config_dir
@@ -281,6 +364,87 @@ fn customfile_path(app_name:&String,filename:impl Into<String>) -> std::io::Resu
     }
 }
 
+/// Suffix of the temporary files written by `atomic_write`.
+const TEMP_SUFFIX: &str = ".prefstore-tmp";
+
+/// Writes `contents` to `path` through a temporary file in the same directory that is
+/// then renamed over `path`, so readers see either the old or the new contents in full.
+fn atomic_write(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    let file_name = path.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no file name"))?
+        .to_string_lossy();
+    let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let tmp = path.with_file_name(format!(".{}.{}.{}{}", file_name, std::process::id(), n, TEMP_SUFFIX));
+
+    let result = permissions::open_for(&tmp, path, OpenOptions::new().write(true).create_new(true))
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp);
+    }
+    result
+}
+
+/// Reads a stored value to report it to observers, or `None` if it does not exist.
+fn read_reported_value(path: &Path) -> Option<String> {
+    std::fs::read(path).ok().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Runs `write`, which changes the file at `path` inside the app's folder, and reports the
+/// change to the app's audit log, history and observers. Every function that changes stored values
+/// goes through here.
+fn tracked_write<R>(app_name: &str, path: &Path, write: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R> {
+    tracked_write_with(app_name, path, read_reported_value, write)
+}
+
+/// Like `tracked_write`, with `read` deciding what value the file holds.
+fn tracked_write_with<R>(app_name: &str, path: &Path, read: impl Fn(&Path) -> Option<String>, write: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R> {
+    let audited = audit::is_enabled(app_name);
+    let tracked = history::is_enabled(app_name);
+    if !audited && !tracked && !observe::has_observers(app_name) {
+        let result = write()?;
+        record_integrity(path)?;
+        return Ok(result);
+    }
+    let old = read(path);
+    let before = if tracked { Some(history::Before::read(path)) } else { None };
+    let result = write()?;
+    record_integrity(path)?;
+    let new = read(path);
+    if let Some(before) = before {
+        history::record(app_name, path, before, read_reported_value(path).as_deref())?;
+    }
+    if old != new {
+        let key = relative_key(&config_folder_path(app_name)?, path)?;
+        if audited {
+            audit::record(app_name, &key, old.as_deref(), new.as_deref())?;
+        }
+        observe::notify(WriteEvent { app_name: app_name.to_string(), key, old, new });
+    }
+    Ok(result)
+}
+
+/// Updates the integrity record of the file at `path` after it was written or removed.
+#[cfg_attr(not(feature = "integrity"), allow(unused_variables))]
+fn record_integrity(path: &Path) -> std::io::Result<()> {
+    #[cfg(feature = "integrity")]
+    integrity::after_write(path)?;
+    Ok(())
+}
+
+/// Checks `contents`, just read from `path`, against its integrity record.
+#[cfg_attr(not(feature = "integrity"), allow(unused_variables))]
+fn verify_integrity(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    #[cfg(feature = "integrity")]
+    integrity::verify(path, contents)?;
+    Ok(())
+}
+
 /// Removes the preference with the given key for the given app_name.
 ///
 /// # Arguments
@@ -300,9 +464,16 @@ fn customfile_path(app_name:&String,filename:impl Into<String>) -> std::io::Resu
 ///
 /// This function will return an error if it is unable to remove the preference file.
 pub fn clearpreference(app_name:impl Into<String>,key: impl Into<String>) -> std::io::Result<()> {
-    let path = config_path(&app_name.into(),&key.into())?;
-    remove_file(&path)?;
-    Ok(())
+    let app_name = app_name.into();
+    let key = alias::resolve(&app_name, &key.into());
+    let path = config_path(&app_name,&key)?;
+    let result = tracked_write(&app_name, &path, || remove_file(&path));
+    // Old names of the key are cleared too, or reading the key would fall back to them.
+    let removed_old = alias::remove_old(&app_name, &key, true)?;
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && removed_old => Ok(()),
+        result => result,
+    }
 }
 /// Deletes the custom file with the given name for the specified app.
 ///
@@ -318,45 +489,380 @@ pub fn clearpreference(app_name:impl Into<String>,key: impl Into<String>) -> std
 /// # Examples
 ///
 /// ```
-/// use std::path::PathBuf;
-/// use std::fs::{self, File};
-///
-/// fn customfile_path(app_name:&String,filename:impl Into<String>) -> PathBuf {
-///     /* Function body */
-///     # PathBuf::new()
-/// }
-///
-/// fn clearcustom_test() {
-///     let app_name = "MyApp";
-///     let custom_filename_with_extension = "myfile.txt";
-///
-///     // Create the custom file to be deleted
-///     let file_path = customfile_path(&app_name.to_string(), custom_filename_with_extension.to_string());
-///     File::create(&file_path).unwrap();
+/// use prefstore::{clearcustom, prefstore_directory, savecustom};
 ///
-///     // Ensure the custom file exists
-///     assert_eq!(file_path.exists(), true);
+/// let app_name = "MyApp";
+/// let custom_filename_with_extension = "myfile.txt";
 ///
-///     // Delete the custom file
-///     clearcustom(&app_name.to_string(), custom_filename_with_extension.to_string());
+/// // Create the custom file to be deleted
+/// savecustom(app_name, custom_filename_with_extension, "value").unwrap();
+/// let file_path = prefstore_directory(&app_name.to_string()).unwrap().join(custom_filename_with_extension);
+/// assert_eq!(file_path.exists(), true);
 ///
-///     // Ensure the custom file was deleted
-///     assert_eq!(file_path.exists(), false);
-/// }
+/// // Delete the custom file
+/// clearcustom(app_name, custom_filename_with_extension);
+/// assert_eq!(file_path.exists(), false);
 /// ```
 pub fn clearcustom(app_name:impl Into<String>,custom_filename_with_extension: impl Into<String>){
-    let path = match customfile_path(&app_name.into(),&custom_filename_with_extension.into()) {
+    let app_name = app_name.into();
+    let path = match customfile_path(&app_name,custom_filename_with_extension.into()) {
         Ok(path) => path,
         Err(_) => return,
     };
 
-    match remove_file(&path) {
-        Ok(_) => {},
-        Err(_) => {},
+    let _ = tracked_write(&app_name, &path, || remove_file(&path));
+}
+/// How far below a namespace the bulk functions look for files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// Only files directly inside the namespace directory.
+    Shallow,
+    /// Files in the namespace directory and all of its sub-directories.
+    Recursive,
+}
+
+/// Returns the directory of a namespace inside the app's configuration folder.
+///
+/// A namespace is a sub-directory such as `"custom_scripts"` or `"themes/dark"`. An empty
+/// `sub_path` is the configuration folder itself.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if `sub_path` is absolute or contains `..`, so a
+/// namespace can never point outside the app's folder.
+fn namespace_path(app_name: &str, sub_path: &str) -> std::io::Result<PathBuf> {
+    let sub_path = Path::new(sub_path);
+    if sub_path.components().any(|c| !matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir)) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid namespace {:?}", sub_path)));
+    }
+    Ok(config_folder_path(app_name)?.join(sub_path))
+}
+
+/// Builds the glob pattern matching files with the given extension in a namespace.
+fn namespace_glob(app_name: &str, sub_path: &str, file_extension: &str, depth: Depth) -> std::io::Result<String> {
+    let dir = namespace_path(app_name, sub_path)?;
+    let dir_str = dir
+        .to_str()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path characters"))?;
+    let dir_pattern = glob::Pattern::escape(dir_str.trim_end_matches('/'));
+    Ok(match depth {
+        Depth::Shallow => format!("{}/*.{}", dir_pattern, file_extension),
+        Depth::Recursive => format!("{}/**/*.{}", dir_pattern, file_extension),
+    })
+}
+
+/// Lists the files with the given extension in a namespace of the application's configuration folder.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `sub_path` - The namespace to list, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extension` - The extension of the files to be listed.
+/// * `depth` - Whether files in sub-directories of the namespace are included.
+///
+/// # Returns
+///
+/// The paths of the matching files, sorted.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{listcustomwithin, Depth};
+/// let scripts = listcustomwithin("myapp", "custom_scripts", "fds", Depth::Shallow).unwrap();
+/// ```
+pub fn listcustomwithin(app_name: impl Into<String>, sub_path: &str, file_extension: &str, depth: Depth) -> std::io::Result<Vec<PathBuf>> {
+    namespace_files(&app_name.into(), sub_path, &[file_extension], depth)
+}
+
+/// Returns the sorted, de-duplicated paths of the files in a namespace that have any of
+/// the given extensions, together with any errors met while walking the namespace.
+fn namespace_scan(app_name: &str, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<(Vec<PathBuf>, Vec<glob::GlobError>)> {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+    for file_extension in file_extensions {
+        let gh = namespace_glob(app_name, sub_path, file_extension, depth)?;
+        for entry in glob::glob(&gh).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))? {
+            match entry {
+                Ok(path) => {
+                    if path.is_file() {
+                        paths.push(path);
+                    }
+                }
+                Err(e) => {
+                    errors.push(e);
+                }
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok((paths, errors))
+}
+
+/// Like `namespace_scan`, but fails with the first error met while walking the namespace.
+fn namespace_files(app_name: &str, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<Vec<PathBuf>> {
+    let (paths, errors) = namespace_scan(app_name, sub_path, file_extensions, depth)?;
+    match errors.into_iter().next() {
+        Some(e) => Err(glob_error(e)),
+        None => Ok(paths),
+    }
+}
+
+/// Converts an error met while walking a namespace, keeping its kind and the path in the message.
+fn glob_error(e: glob::GlobError) -> std::io::Error {
+    std::io::Error::new(e.error().kind(), e.to_string())
+}
+
+/// Returns the key of a file inside the app's configuration folder: its path relative to
+/// the folder, with `/` separators and the extension kept, as accepted by `getcustom`.
+fn relative_key(app_dir: &Path, path: &Path) -> std::io::Result<String> {
+    let relative = path.strip_prefix(app_dir)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?} is outside the config folder", path)))?;
+    let mut parts = Vec::new();
+    for component in relative.components() {
+        parts.push(component.as_os_str().to_str()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "could not convert file name to str"))?);
+    }
+    Ok(parts.join("/"))
+}
+
+/// Whether `key`, relative to the app's folder, matches `pattern` with or without its
+/// extension. `*` does not match `/`.
+fn key_matches(pattern: &glob::Pattern, key: &str) -> bool {
+    let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+    let stem = match Path::new(key).extension() {
+        Some(extension) => &key[..key.len() - extension.len() - 1],
+        None => key,
     };
+    pattern.matches_with(key, options) || pattern.matches_with(stem, options)
+}
+
+/// Lists the keys of the files with any of the given extensions in a namespace of the
+/// application's configuration folder.
+///
+/// Unlike `getallcustomwithin`, which names entries after the bare file stem, each key is
+/// the file's path relative to the configuration folder, so `a/theme.txt` and `b/theme.txt`
+/// stay distinct and every key can be passed straight to `getcustom` or `clearcustom`.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `sub_path` - The namespace to list, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extensions` - The extensions of the files to be listed.
+/// * `depth` - Whether files in sub-directories of the namespace are included.
+///
+/// # Returns
+///
+/// The keys, sorted.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{listcustomkeys, Depth};
+/// let keys = listcustomkeys("myapp", "", &["txt", "json"], Depth::Recursive).unwrap();
+/// for key in keys {
+///     println!("{}", key);
+/// }
+/// ```
+pub fn listcustomkeys(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<Vec<String>> {
+    let app_name = app_name.into();
+    let app_dir = config_folder_path(&app_name)?;
+    namespace_files(&app_name, sub_path, file_extensions, depth)?
+        .iter()
+        .map(|path| relative_key(&app_dir, path))
+        .collect()
+}
+
+/// Reads the raw bytes of every file with any of the given extensions in a namespace of
+/// the application's configuration folder, with a separate result for each key.
+///
+/// A file that cannot be read, or a directory that cannot be walked, produces an `Err`
+/// entry under its key while every other entry is still returned.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `sub_path` - The namespace to read, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extensions` - The extensions of the files to be read.
+/// * `depth` - Whether files in sub-directories of the namespace are read too.
+///
+/// # Returns
+///
+/// A map from keys, as returned by `listcustomkeys`, to the file contents or the error met
+/// reading them. The call itself only fails if the namespace cannot be resolved.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{readallcustombytes, Depth};
+/// for (key, contents) in readallcustombytes("myapp", "", &["bin"], Depth::Recursive).unwrap() {
+///     match contents {
+///         Ok(bytes) => println!("{}: {} bytes", key, bytes.len()),
+///         Err(e) => eprintln!("{}: {}", key, e),
+///     }
+/// }
+/// ```
+pub fn readallcustombytes(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<BTreeMap<String, std::io::Result<Vec<u8>>>> {
+    readallcustombytes_with(app_name, sub_path, file_extensions, depth, &ReadOptions::default())
+}
+
+/// Like `readallcustombytes`, with explicit [`ReadOptions`], for example to read the
+/// values of sensitive keys.
+pub fn readallcustombytes_with(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth, options: &ReadOptions) -> std::io::Result<BTreeMap<String, std::io::Result<Vec<u8>>>> {
+    let app_name = app_name.into();
+    let app_dir = config_folder_path(&app_name)?;
+    let (paths, errors) = namespace_scan(&app_name, sub_path, file_extensions, depth)?;
+
+    let mut entries = BTreeMap::new();
+    for path in paths {
+        let key = relative_key(&app_dir, &path).unwrap_or_else(|_| path.display().to_string());
+        let contents = std::fs::read(&path).and_then(|contents| verify_integrity(&path, &contents).map(|_| contents));
+        let contents = match !options.reveal_sensitive && sensitive::is_sensitive(&app_name, &key) {
+            true => contents.map(|_| REDACTED.as_bytes().to_vec()),
+            false => contents,
+        };
+        entries.insert(key, contents);
+    }
+    for e in errors {
+        let key = relative_key(&app_dir, e.path()).unwrap_or_else(|_| e.path().display().to_string());
+        entries.insert(key, Err(glob_error(e)));
+    }
+    Ok(entries)
+}
+
+/// Reads every file with any of the given extensions in a namespace of the application's
+/// configuration folder as text, with a separate result for each key.
+///
+/// Contents that are not valid UTF-8 are decoded lossily instead of failing. See
+/// `readallcustombytes` for the raw bytes and for how errors are reported.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{readallcustom, Depth};
+/// let entries = readallcustom("myapp", "", &["txt"], Depth::Recursive).unwrap();
+/// let failed: Vec<&String> = entries.iter().filter(|(_, value)| value.is_err()).map(|(key, _)| key).collect();
+/// ```
+pub fn readallcustom(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<BTreeMap<String, std::io::Result<String>>> {
+    readallcustom_with(app_name, sub_path, file_extensions, depth, &ReadOptions::default())
 }
+
+/// Like `readallcustom`, with explicit [`ReadOptions`], for example to read the values of
+/// sensitive keys.
+pub fn readallcustom_with(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth, options: &ReadOptions) -> std::io::Result<BTreeMap<String, std::io::Result<String>>> {
+    Ok(readallcustombytes_with(app_name, sub_path, file_extensions, depth, options)?
+        .into_iter()
+        .map(|(key, contents)| (key, contents.map(|bytes| String::from_utf8_lossy(&bytes).into_owned())))
+        .collect())
+}
+
+/// A stored key and its file metadata, as returned by `list_keys`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    /// The key, relative to the app's configuration folder, as accepted by `getcustom`.
+    pub key: String,
+    /// The file backing the key.
+    pub path: PathBuf,
+    /// The size of the stored value in bytes.
+    pub size: u64,
+    /// When the value was last changed, if the platform reports it.
+    pub modified: Option<SystemTime>,
+    /// When the file was created, if the platform and filesystem report it.
+    pub created: Option<SystemTime>,
+}
+
+/// Lists every key stored in a namespace of the application's configuration folder,
+/// including its sub-directories, together with each file's size and timestamps.
+///
+/// Only file metadata is read, never file contents, so this is cheap enough for a
+/// settings screen. Hidden files, such as the lock and temporary files prefstore uses
+/// internally, are skipped.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `namespace` - The namespace to list, relative to the configuration folder. Use `""` for the whole folder.
+///
+/// # Returns
+///
+/// The keys sorted by name, or an empty vector if the namespace does not exist.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::list_keys;
+/// for info in list_keys("myapp", "").unwrap() {
+///     println!("{} ({} bytes, changed {:?})", info.key, info.size, info.modified);
+/// }
+/// ```
+pub fn list_keys(app_name: impl Into<String>, namespace: &str) -> std::io::Result<Vec<KeyInfo>> {
+    let app_name = app_name.into();
+    let app_dir = config_folder_path(&app_name)?;
+    let mut keys = Vec::new();
+    let mut dirs = vec![namespace_path(&app_name, namespace)?];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if metadata.is_file() {
+                keys.push(KeyInfo {
+                    key: relative_key(&app_dir, &path)?,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                    created: metadata.created().ok(),
+                    path,
+                });
+            }
+        }
+    }
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(keys)
+}
+
+/// Retrieves the contents of the files with any of the given extensions in a namespace of
+/// the application's configuration folder, keyed by their path relative to the folder.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `sub_path` - The namespace to read, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extensions` - The extensions of the files to be retrieved.
+/// * `depth` - Whether files in sub-directories of the namespace are read too.
+///
+/// # Returns
+///
+/// A map from keys, as returned by `listcustomkeys`, to file contents, in key order.
+/// Contents that are not valid UTF-8 are decoded lossily. Fails on the first file that
+/// cannot be read or does not pass its integrity check.
+pub fn getallcustomkeyed(app_name: impl Into<String>, sub_path: &str, file_extensions: &[&str], depth: Depth) -> std::io::Result<BTreeMap<String, String>> {
+    let app_name = app_name.into();
+    let app_dir = config_folder_path(&app_name)?;
+    let mut entries = BTreeMap::new();
+    for path in namespace_files(&app_name, sub_path, file_extensions, depth)? {
+        let key = relative_key(&app_dir, &path)?;
+        let contents = std::fs::read(&path)?;
+        verify_integrity(&path, &contents)?;
+        let value = sensitive::redact(&app_name, &key, String::from_utf8_lossy(&contents).into_owned());
+        entries.insert(key, value);
+    }
+    Ok(entries)
+}
+
 /// Clears all files with the given extension in the configuration folder for the given application.
 ///
+/// A snapshot of the folder is taken first, see [`list_snapshots`].
+///
 /// # Arguments
 ///
 /// * `app_name` - The name of the application whose files should be cleared.
@@ -370,35 +876,55 @@ pub fn clearcustom(app_name:impl Into<String>,custom_filename_with_extension: im
 /// ```
 pub fn clearall(app_name: impl Into<String>, file_extension: &str) -> std::io::Result<()> {
     let app_name = app_name.into();
+    snapshot::snapshot_before(&app_name, "clearall")?;
+    clearallwithin(app_name, "", file_extension, Depth::Recursive)
+}
 
-    // Construct the path to the configuration folder for the application.
-    let config_path = config_folder_path(&app_name)?;
-    let path_str = config_path
-        .to_str()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path characters"))?
-        .to_string();
-
-    // Append the glob pattern to match files with the given extension.
-    let gh = format!("{}/**/*.{}", path_str, file_extension);
-
-    // Iterate over all files that match the glob pattern and attempt to remove them.
-    for entry in glob::glob(&gh).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))? {
-        match entry {
-            Ok(path) => {
-                if let Err(e) = remove_file(path) {
-                    eprintln!("Failed to remove file: {:?}", e);
-                }
-            }
-            Err(e) => {
-                eprintln!("error with glob {:?}", e);
-            }
+/// Clears all files with the given extension in a namespace of the application's configuration folder.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application whose files should be cleared.
+/// * `sub_path` - The namespace to clear, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extension` - The extension of the files to be cleared.
+/// * `depth` - Whether files in sub-directories of the namespace are cleared too.
+///
+/// # Errors
+///
+/// Every file that can be removed is removed first. The error then reports the files that
+/// could not be removed or the directories that could not be walked.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{clearallwithin, Depth};
+/// clearallwithin("myapp", "custom_scripts", "fds", Depth::Recursive);
+/// ```
+pub fn clearallwithin(app_name: impl Into<String>, sub_path: &str, file_extension: &str, depth: Depth) -> std::io::Result<()> {
+    let app_name = app_name.into();
+    let (paths, errors) = namespace_scan(&app_name, sub_path, &[file_extension], depth)?;
+    let mut errors: Vec<std::io::Error> = errors.into_iter().map(glob_error).collect();
+    // Remove every file that can be removed before reporting the ones that could not.
+    for path in paths {
+        match tracked_write(&app_name, &path, || remove_file(&path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                errors.push(std::io::Error::new(e.kind(), format!("could not remove {}: {}", path.display(), e)));
+            },
+            _ => {},
         }
     }
-    Ok(())
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        count => Err(std::io::Error::new(errors[0].kind(), format!("{} files could not be removed, first: {}", count, errors[0]))),
+    }
 }
 // #[no_mangle]
 /// Retrieves the preference with the given key for the given app_name. If the preference does not exist, it returns the default value provided.
 ///
+/// If the app's schema declares the key, the declared default is used instead of `defvalue`,
+/// and a stored value the schema does not accept is replaced by that default.
+///
 /// # Arguments
 ///
 /// * `app_name` - A string slice that holds the name of the application.
@@ -421,8 +947,18 @@ pub fn clearall(app_name: impl Into<String>, file_extension: &str) -> std::io::R
 ///
 /// This function will return an error if it is unable to read the preference file or create a new preference file with the default value.
 pub fn getpreference<T:ToString>(app_name:impl Into<String>,key:impl Into<String>,defvalue:T)->String{
+    let app_name = app_name.into();
+    let key = alias::resolve(&app_name, &key.into());
+    if let Some(value) = schema::declared_preference(&app_name, &key) {
+        return value;
+    }
     let defvalue_str = defvalue.to_string();
-    getcustom(app_name, format!("{}.txt", key.into()), defvalue).unwrap_or(defvalue_str)
+    match alias::fallback(&app_name, &key) {
+        Ok(Some(value)) => return value,
+        Ok(None) => {},
+        Err(_) => return defvalue_str,
+    }
+    getcustom(app_name, format!("{}.txt", key), defvalue).unwrap_or(defvalue_str)
 }
 /// Retrieve the custom data from the specified custom file for the specified app,
 /// or save the default value and return it if the file does not exist.
@@ -441,25 +977,27 @@ pub fn getpreference<T:ToString>(app_name:impl Into<String>,key:impl Into<String
 /// # Examples
 ///
 /// ```
+/// use prefstore::getcustom;
+///
 /// let app_name = "MyApp";
 /// let key = "my_custom_data.txt";
 /// let custom_data = getcustom(app_name, key, "default value");
-/// println!("Custom data: {}", custom_data);
+/// println!("Custom data: {:?}", custom_data);
 /// ```
-
 pub fn getcustom<T:ToString>(app_name:impl Into<String>,key:impl Into<String>,defvalue:T)->std::io::Result<String>{
     let key =key.into();
     let app_name =app_name.into();
-    if(!key.is_empty()){
+    if !key.is_empty() {
         let path = customfile_path(&app_name,&key)?;
-        match(File::open(&path)){
+        match File::open(&path){
             Ok(mut file) => {
                 let mut buf = String::new();
                 file.read_to_string(&mut buf)?;
+                verify_integrity(&path, buf.as_bytes())?;
                 Ok(buf)
             },
             Err(_) => {
-                savecustom(app_name,&key, &defvalue.to_string())?;
+                savecustom(app_name,&key, defvalue.to_string())?;
                 Ok(defvalue.to_string())
             },
         }
@@ -515,19 +1053,25 @@ pub fn opencustomperlinetovec(app_name: impl Into<String>, key: impl Into<String
 /// # Examples
 ///
 /// ```
+/// use prefstore::getpreferencenodefault;
+///
 /// let app_name = "my_app";
 /// let key = "my_preference_key";
 /// let preference_value = getpreferencenodefault(app_name, key);
 /// ```
 pub fn getpreferencenodefault(app_name:impl Into<String>,key:impl Into<String>)->std::io::Result<String>{
     use io::Read;
-    let key =key.into();
     let app_name =app_name.into();
+    let key =alias::resolve(&app_name, &key.into());
+    if let Some(value) = alias::fallback(&app_name, &key)? {
+        return Ok(value);
+    }
     let path = config_path(&app_name,&key)?;
-    match(File::open(&path)){
+    match File::open(&path){
         Ok(mut file) => {
             let mut buf = String::new();
             file.read_to_string(&mut buf)?;
+            verify_integrity(&path, buf.as_bytes())?;
             Ok(buf)
         },
         Err(_) => {
@@ -536,6 +1080,8 @@ pub fn getpreferencenodefault(app_name:impl Into<String>,key:impl Into<String>)-
     }
 }
 
+// The lowercase name is public API and kept for compatibility.
+#[allow(non_camel_case_types)]
 pub trait ems<T>{
     /// Converts a String to a bool.
     ///
@@ -548,7 +1094,7 @@ pub trait ems<T>{
     /// ```
     /// use prefstore::ems;
     ///
-    /// let value = "true";
+    /// let value = "true".to_string();
     /// let bool_value = value.tobool();
     /// assert_eq!(bool_value, true);
     /// ```
@@ -569,7 +1115,7 @@ pub trait ems<T>{
     /// ```
     /// use prefstore::ems;
     ///
-    /// let value = "42";
+    /// let value = "42".to_string();
     /// let i32_value = value.toi32().unwrap();
     /// assert_eq!(i32_value, 42);
     /// ```
@@ -590,7 +1136,7 @@ pub trait ems<T>{
     /// ```
     /// use prefstore::ems;
     ///
-    /// let value = "99999999999999999999999999999999999999";
+    /// let value = "99999999999999999999999999999999999999".to_string();
     /// let i128_value = value.toi128().unwrap();
     /// assert_eq!(i128_value, 99999999999999999999999999999999999999);
     /// ```
@@ -611,7 +1157,7 @@ pub trait ems<T>{
     /// ```
     /// use prefstore::ems;
     ///
-    /// let value = "3.14";
+    /// let value = "3.14".to_string();
     /// let f64_value = value.tof64().unwrap();
     /// assert_eq!(f64_value, 3.14);
     /// ```
@@ -677,7 +1223,7 @@ pub fn config_folder_path(app_name: &str) -> std::io::Result<PathBuf> {
 /// # Returns
 ///
 /// A vector of tuples containing file names and their contents.
-/// ```
+/// ```text
 /// vec![
 ///     ("file1".to_owned(), "content1".to_owned()),
 ///     ("file2".to_owned(), "content2".to_owned()),
@@ -697,26 +1243,51 @@ pub fn getall(app_name: impl Into<String>) -> std::io::Result<Vec<(String, Strin
     getallcustom(app_name, "txt")
 }
 
+/// Like `getall`, with explicit [`ReadOptions`], for example to read the values of
+/// sensitive keys.
+pub fn getall_with(app_name: impl Into<String>, options: &ReadOptions) -> std::io::Result<Vec<(String, String)>> {
+    let entries = readallcustom_with(app_name, "", &["txt"], Depth::Recursive, options)?;
+    let mut list = by_file_name(entries).into_iter()
+        .map(|(file_name, input)| Ok((file_name, input?)))
+        .collect::<std::io::Result<Vec<(String, String)>>>()?;
+    list.sort();
+    Ok(list)
+}
+
 /// Saves the given value to the buffer for the given app name and custom filename with extension.
 ///
+/// The value is appended to the end of the buffer file and the oldest entries beyond
+/// `buffersize` are skipped over rather than rewritten, so saving costs a single file
+/// open however large the buffer grows. The skipped space is reclaimed periodically.
+///
 /// # Arguments
 ///
 /// * `app_name`: The name of the app.
 /// * `custom_filename_with_extension`: The custom filename with extension.
-/// * `value`: The value to save.
-/// * `buffersize`: The maximum number of strings to keep in the buffer.
+/// * `value`: The value to save. Empty values are ignored and each line of a multi-line value is saved as its own entry.
+/// * `buffersize`: The maximum number of strings to keep in the buffer. A negative size
+///   keeps every string.
 ///
 /// # Returns
 ///
-/// A Result with Ok if successful or an IO error.
+/// A Result with Ok if successful or an IO error. A file that stores a preference
+/// declared in the app's schema cannot hold a buffer, and fails with `InvalidInput`.
 pub fn savebuffer(app_name: impl Into<String>, custom_filename_with_extension: impl Into<String>, value: impl Into<String>, buffersize: i8) -> std::io::Result<()> {
-    // getcustom(app_name, key, defvalue);
     let app_name = app_name.into();
     let filename = custom_filename_with_extension.into();
-    let stringtos = value.into();
-    let vectos = saveStringtobuffer(&app_name, stringtos, buffersize, &filename)?;
-    // vectos.reverse();
-    saveVecOfStrings(&app_name, vectos, &filename)
+    let fname = "#savebuffer";
+
+    let path = customfile_path(&app_name, &filename)?;
+    let parent_path = path.parent()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Cannot find path to {}", fname)))?;
+    if schema::spec_for_file(&app_name, &filename).is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} is declared in the schema and cannot hold a buffer", filename)));
+    }
+
+    permissions::create_dirs(parent_path)?;
+
+    let read_buffer = |path: &Path| buffer::read(path).ok().filter(|entries| !entries.is_empty()).map(|entries| entries.join("\n"));
+    tracked_write_with(&app_name, &path, read_buffer, || buffer::push(&path, &value.into(), usize::try_from(buffersize).unwrap_or(usize::MAX)))
 }
 
 /// Gets the last string from the buffer for the given app name and custom filename with extension.
@@ -734,58 +1305,6 @@ pub fn get_last_from_buffer(app_name: impl Into<String>, custom_filename_with_ex
     Ok(buffer.last().unwrap_or(&String::new()).clone())
 }
 
-/// Saves the given string to the buffer for the given app name and filename.
-///
-/// # Arguments
-///
-/// * `app_name`: The name of the app.
-/// * `string`: The string to save.
-/// * `n`: The maximum number of strings to keep in the buffer.
-/// * `filename`: The filename of the buffer.
-///
-/// # Returns
-///
-/// A Result containing the updated buffer or an IO error.
-fn saveStringtobuffer(app_name: &str, string: String, n: i8, filename: &str) -> std::io::Result<Vec<String>> {
-    let mut ds = getbuffer(app_name, filename)?;
-    // Push the new string to the end of the vector
-    ds.push(string.clone());
-    // println!("{}", string);
-    // println!("{}/{}--------{:?}", ds.len(), n as usize, ds);
-    if ds.len() > n as usize {
-        println!("removing");
-        ds.remove(0);
-    }
-    Ok(ds)
-}
-
-/// Saves a vector of strings to a file.
-///
-/// # Arguments
-///
-/// * `app_name`: The name of the app.
-/// * `strings`: The vector of strings to save.
-/// * `file_name`: The filename of the file to save to.
-///
-/// # Returns
-///
-/// A `Result` with an `Ok` value if the operation was successful, or an `Err` value
-/// containing an error message if the operation failed.
-fn saveVecOfStrings(app_name: &str, strings: Vec<String>, file_name: &str) -> Result<(), Error> {
-    // Open the file for writing, creating it if it doesn't exist
-    let _ = opencustomperlinetovec(app_name, file_name)?;
-    clearcustom(app_name, file_name);
-    initcustomfile(app_name, file_name, "")?;
-    // Iterate over the strings in the vector
-    for string in strings {
-        if !string.is_empty() {
-            appendcustomnewline(app_name, file_name, string)?;
-        }
-    }
-    // Return Ok if no errors occurred
-    Ok(())
-}
-
 /// Gets the buffer for the given app name and file name.
 ///
 /// # Arguments
@@ -795,9 +1314,15 @@ fn saveVecOfStrings(app_name: &str, strings: Vec<String>, file_name: &str) -> Re
 ///
 /// # Returns
 ///
-/// A Result containing a vector of strings with the buffer contents or an IO error.
+/// A Result containing a vector of strings with the buffer contents, oldest first, or an IO error.
 pub fn getbuffer(app_name: &str, file_name: &str) -> std::io::Result<Vec<String>> {
-    opencustomperlinetovec(app_name, file_name)
+    let path = customfile_path(&app_name.to_string(), file_name)?;
+    match std::fs::read(&path) {
+        Ok(contents) => verify_integrity(&path, &contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    buffer::read(&path)
 }
 
 /// Retrieves the contents of all files with the given extension in the configuration folder for the given application.
@@ -809,78 +1334,74 @@ pub fn getbuffer(app_name: &str, file_name: &str) -> std::io::Result<Vec<String>
 ///
 /// # Returns
 ///
-/// A vector of tuples representing file names and their contents.
+/// A vector of tuples representing file names and their contents, sorted by file name.
 ///
 /// # Examples
 ///
 /// ```
 /// # use prefstore::getallcustom;
-/// let files = getallcustom("myapp", "txt");
+/// let files = getallcustom("myapp", "txt").unwrap();
 /// for (name, contents) in files {
 ///     println!("{}: {}", name, contents);
 /// }
 /// ```
 pub fn getallcustom(app_name:impl Into<String>,file_extension:&str)->std::io::Result<Vec<(String,String)>>{
     let map = getallcustomwithin(app_name,"", file_extension)?;
-    Ok(map.into_iter().collect())
+    let mut list: Vec<(String,String)> = map.into_iter().collect();
+    list.sort();
+    Ok(list)
 }
+/// Retrieves the contents of all files with the given extension in a namespace of the
+/// application's configuration folder, including its sub-directories.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application whose files should be retrieved.
+/// * `sub_path` - The namespace to read, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extension` - The extension of the files to be retrieved.
+///
+/// # Returns
+///
+/// A map from file names, without their extension, to their contents.
+///
+/// # Errors
+///
+/// Fails if any matching file cannot be read. Use `readallcustom` to get the readable
+/// entries along with a separate error for each unreadable one.
 pub fn getallcustomwithin(app_name:impl Into<String>,sub_path:&str,file_extension:&str)->std::io::Result<HashMap<String,String>>{
-    let app_name=app_name.into();
-    // println!("{app_name}");
-    let config_path = config_folder_path(&app_name)?;
-    let path_str = config_path
-        .to_str()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "could not find config folder path"))?
-        .to_string();
-    // println!("{}",path_str);
-    // let key =key.into();
-    let y=format!("/{}",sub_path);
-    let subpath=if(sub_path==""){
-        ""}else{
-           &y};
-    let gh = format!("{}/**/*.{}",path_str,file_extension);
-    let mut list_of_strings:HashMap<String,String>=HashMap::new();
-    // println!("{:?}-----------------{:?}",gh,glob::glob(&gh).expect("Failed to read glob pattern"));
-    for entry in glob::glob(&gh).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))? {
-        match entry {
-
-            Ok(path) =>{
-                let input= match(File::open(&path)){
-                    Ok(mut file) => {
-                        let mut buf = String::new();
-                        file.read_to_string(&mut buf)?;
-                        buf
-                        // file
-                    },
-                    Err(_) => {
-                        return Ok(HashMap::new())
-                        // savepreference(app_name,&key, &defvalue.to_string());
-                        // defvalue.to_string()
-                    },
-                };
-                // let vec_of_string = input.split("\n").map(|s| getdecoded(s).to_string()).collect::<Vec<String>>().join("\n");
-                println!("{:?}",input);
-                // let listdecoded:Vec<String>=readserdefromfile(&input).unwrap();
-                let file_name = path.file_stem()
-                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no file name found"))?
-                .to_str()
-                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "could not convert file name to str"))?
-                .to_string();
-
-                // for i in vec_of_string{
-                    list_of_strings.insert(file_name.to_owned(),input);
-                // }
+    getallcustomwithindepth(app_name, sub_path, file_extension, Depth::Recursive)?
+        .into_iter()
+        .map(|(file_name, input)| Ok((file_name, input?)))
+        .collect()
+}
 
-            },
-            Err(e) => {
-                eprintln!("error with glob {:?}", e);
+/// Retrieves the contents of all files with the given extension in a namespace of the
+/// application's configuration folder.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application whose files should be retrieved.
+/// * `sub_path` - The namespace to read, relative to the configuration folder. Use `""` for the whole folder.
+/// * `file_extension` - The extension of the files to be retrieved.
+/// * `depth` - Whether files in sub-directories of the namespace are read too.
+///
+/// # Returns
+///
+/// A map from file names, without their extension, to their contents or the error met
+/// reading them, as with `readallcustom`. The call itself only fails if the namespace
+/// cannot be resolved.
+pub fn getallcustomwithindepth(app_name:impl Into<String>,sub_path:&str,file_extension:&str,depth:Depth)->std::io::Result<HashMap<String,std::io::Result<String>>>{
+    Ok(by_file_name(readallcustom(app_name, sub_path, &[file_extension], depth)?))
+}
 
-            },
-        }
+/// Re-keys the entries of `readallcustom` by file name without extension.
+fn by_file_name(entries: BTreeMap<String, std::io::Result<String>>) -> HashMap<String, std::io::Result<String>> {
+    let mut list_of_strings:HashMap<String,std::io::Result<String>>=HashMap::new();
+    for (key, input) in entries {
+        let file_name = Path::new(&key).file_stem().map_or_else(|| key.clone(), |stem| stem.to_string_lossy().into_owned());
+        list_of_strings.insert(file_name, input);
     }
-
-
-    Ok(list_of_strings)
+    list_of_strings
 }
 
 #[cfg(test)]
@@ -889,9 +1410,10 @@ mod prefstore_test {
     
     #[test]
     fn test_getall() {
-        savecustom("myapp", "custom1", "value1");
-        savecustom("myapp", "custom2", "value2");
-        let all_custom = getall("myapp").unwrap();
+        clearall("myapp_getall", "txt").unwrap();
+        savecustom("myapp_getall", "custom1.txt", "value1").unwrap();
+        savecustom("myapp_getall", "custom2.txt", "value2").unwrap();
+        let all_custom = getall("myapp_getall").unwrap();
         assert_eq!(all_custom.len(), 2);
         assert!(all_custom.contains(&("custom1".to_string(), "value1".to_string())));
         assert!(all_custom.contains(&("custom2".to_string(), "value2".to_string())));
@@ -911,11 +1433,13 @@ mod prefstore_test {
     }
 
     #[test]
+    // The test compares against the literal 3.14, which clippy denies by default.
+    #[allow(clippy::approx_constant)]
     fn test_savepreference() {
         let app_name = "myapp";
         let key = "mykey";
         let value = "myvalue";
-        savepreference(app_name, key, value);
+        savepreference(app_name, key, value).unwrap();
 
         // Test that the preference was saved correctly
         let result = getpreference(app_name, key, "defaultvalue");
@@ -926,13 +1450,13 @@ mod prefstore_test {
         assert_eq!(result, "defaultvalue");
 
         // Test that the preference can be cleared
-        clearpreference(app_name, key);
+        clearpreference(app_name, key).unwrap();
         let result = getpreference(app_name, key, "defaultvalue");
         assert_eq!(result, "defaultvalue");
 
         // Test that the ems trait works correctly
         let bool_value = "true".to_string().tobool();
-        assert_eq!(bool_value, true);
+        assert!(bool_value);
 
         let i32_value = "42".to_string().toi32().unwrap();
         assert_eq!(i32_value, 42);
@@ -944,17 +1468,113 @@ mod prefstore_test {
         assert_eq!(f64_value, 3.14);
     }
 
-    // #[test]
-    // fn test_buffer(){
-    //     savebuffer("prefstore", "last.save", "yu", 3);
-    //     savebuffer("prefstore", "last.save", "", 3);
-    //     savebuffer("prefstore", "last.save", "yu3", 3);
-    //     savebuffer("prefstore", "last.save", "yu4", 3);
-    //     savebuffer("prefstore", "last.save", "yu5", 3);
-    //     savebuffer("prefstore", "last.save", "yu6", 3);
-    //     println!("{:?}",getbuffer("prefstore", "last.save"));
-    //     println!("{:?}",get_last_from_buffer("prefstore", "last.save"));
-    // }
+    #[test]
+    fn test_buffer(){
+        clearcustom("prefstore", "last.save");
+        savebuffer("prefstore", "last.save", "yu", 3).unwrap();
+        savebuffer("prefstore", "last.save", "", 3).unwrap();
+        savebuffer("prefstore", "last.save", "yu3", 3).unwrap();
+        savebuffer("prefstore", "last.save", "yu4", 3).unwrap();
+        savebuffer("prefstore", "last.save", "yu5", 3).unwrap();
+        savebuffer("prefstore", "last.save", "yu6", 3).unwrap();
+        assert_eq!(getbuffer("prefstore", "last.save").unwrap(), vec!["yu4", "yu5", "yu6"]);
+        assert_eq!(get_last_from_buffer("prefstore", "last.save").unwrap(), "yu6");
+
+        savebuffer("prefstore", "last.save", "yu7", -1).unwrap();
+        assert_eq!(getbuffer("prefstore", "last.save").unwrap(), vec!["yu4", "yu5", "yu6", "yu7"]);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let app_name = "myapp_namespaces";
+        clearall(app_name, "fds").unwrap();
+        savecustom(app_name, "top.fds", "top").unwrap();
+        savecustom(app_name, "custom_scripts/build.fds", "build").unwrap();
+        savecustom(app_name, "custom_scripts/nested/deploy.fds", "deploy").unwrap();
+        savecustom(app_name, "other/lint.fds", "lint").unwrap();
+
+        let scoped = getallcustomwithin(app_name, "custom_scripts", "fds").unwrap();
+        assert_eq!(scoped.len(), 2);
+        assert_eq!(scoped["build"], "build");
+        assert_eq!(scoped["deploy"], "deploy");
+
+        let shallow = getallcustomwithindepth(app_name, "custom_scripts", "fds", Depth::Shallow).unwrap();
+        assert_eq!(shallow.keys().collect::<Vec<_>>(), vec!["build"]);
+        assert_eq!(shallow["build"].as_ref().unwrap(), "build");
+        assert_eq!(getallcustomwithin(app_name, "", "fds").unwrap().len(), 4);
+
+        clearallwithin(app_name, "custom_scripts", "fds", Depth::Shallow).unwrap();
+        assert_eq!(listcustomwithin(app_name, "custom_scripts", "fds", Depth::Recursive).unwrap().len(), 1);
+        assert_eq!(listcustomwithin(app_name, "", "fds", Depth::Shallow).unwrap().len(), 1);
+
+        assert!(listcustomwithin(app_name, "../elsewhere", "fds", Depth::Recursive).is_err());
+    }
+
+    #[test]
+    fn test_listcustomkeys() {
+        let app_name = "myapp_keys";
+        clearall(app_name, "txt").unwrap();
+        clearall(app_name, "json").unwrap();
+        savecustom(app_name, "b/theme.txt", "light").unwrap();
+        savecustom(app_name, "a/theme.txt", "dark").unwrap();
+        savecustom(app_name, "notes.md.txt", "notes").unwrap();
+        savecustom(app_name, "config.json", "{}").unwrap();
+
+        assert_eq!(
+            listcustomkeys(app_name, "", &["txt", "json"], Depth::Recursive).unwrap(),
+            vec!["a/theme.txt", "b/theme.txt", "config.json", "notes.md.txt"]
+        );
+        assert_eq!(listcustomkeys(app_name, "a", &["txt"], Depth::Recursive).unwrap(), vec!["a/theme.txt"]);
+
+        let entries = getallcustomkeyed(app_name, "", &["txt"], Depth::Recursive).unwrap();
+        assert_eq!(entries.into_iter().collect::<Vec<_>>(), vec![
+            ("a/theme.txt".to_string(), "dark".to_string()),
+            ("b/theme.txt".to_string(), "light".to_string()),
+            ("notes.md.txt".to_string(), "notes".to_string()),
+        ]);
+        assert_eq!(getcustom(app_name, "a/theme.txt", "").unwrap(), "dark");
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let app_name = "myapp_list_keys";
+        let _ = std::fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        savecustom(app_name, "theme.txt", "dark").unwrap();
+        savecustom(app_name, "plugins/enabled.json", "[]").unwrap();
+        savecustom(app_name, ".hidden", "x").unwrap();
+
+        let keys = list_keys(app_name, "").unwrap();
+        assert_eq!(keys.iter().map(|k| k.key.as_str()).collect::<Vec<_>>(), vec!["plugins/enabled.json", "theme.txt"]);
+        assert_eq!(keys[1].size, 4);
+        assert!(keys[1].modified.is_some());
+        assert_eq!(keys[1].path, config_folder_path(app_name).unwrap().join("theme.txt"));
+
+        assert_eq!(list_keys(app_name, "plugins").unwrap().len(), 1);
+        assert!(list_keys(app_name, "missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_readallcustom_reports_each_entry() {
+        let app_name = "myapp_readall";
+        clearall(app_name, "dat").unwrap();
+        savecustom(app_name, "good.dat", "fine").unwrap();
+        let binary = config_folder_path(app_name).unwrap().join("binary.dat");
+        std::fs::write(&binary, [0x66, 0x6f, 0xff, 0x6f]).unwrap();
+        // A directory with a matching name cannot be read as a file.
+        create_dir_all(config_folder_path(app_name).unwrap().join("dir.dat")).unwrap();
+
+        let entries = readallcustom(app_name, "", &["dat"], Depth::Shallow).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["good.dat"].as_ref().unwrap(), "fine");
+        assert_eq!(entries["binary.dat"].as_ref().unwrap(), "fo\u{FFFD}o");
+
+        let bytes = readallcustombytes(app_name, "", &["dat"], Depth::Shallow).unwrap();
+        assert_eq!(bytes["binary.dat"].as_ref().unwrap(), &vec![0x66, 0x6f, 0xff, 0x6f]);
+
+        let by_name = getallcustomwithindepth(app_name, "", "dat", Depth::Shallow).unwrap();
+        assert_eq!(by_name["binary"].as_ref().unwrap(), "fo\u{FFFD}o");
+        assert_eq!(by_name.len(), 2);
+    }
 }
 
 // This is a Rust module that provides a preference store. It allows you to save and retrieve preferences for your application. The preferences are stored in the system's configuration directory.
@@ -0,0 +1,167 @@
+//! Typed settings snapshots that reload themselves when preferences change.
+
+use std::{io, sync::{Arc, RwLock}};
+
+use crate::{observe, watch_with, Subscription, WatchOptions, Watcher};
+
+/// A type that can be loaded from an app's stored preferences.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{ems, getpreference, FromPreferences};
+///
+/// struct Settings {
+///     theme: String,
+///     font_size: i32,
+/// }
+///
+/// impl FromPreferences for Settings {
+///     fn from_preferences(app_name: &str) -> std::io::Result<Settings> {
+///         Ok(Settings {
+///             theme: getpreference(app_name, "theme", "light"),
+///             font_size: getpreference(app_name, "font_size", 12).toi32().unwrap_or(12),
+///         })
+///     }
+/// }
+/// ```
+pub trait FromPreferences: Sized {
+    /// Reads the preferences of `app_name` into a new value.
+    fn from_preferences(app_name: &str) -> io::Result<Self>;
+}
+
+type Shared<T> = Arc<RwLock<Arc<T>>>;
+
+/// A snapshot of an app's preferences loaded into `T`, which is replaced whenever the
+/// underlying keys change, whether through the prefstore API in this process or on disk.
+///
+/// Reading is cheap: [`Live::get`] hands out the current snapshot behind an `Arc`
+/// without touching the disk, and a snapshot never changes once handed out.
+///
+/// If reloading fails, for example because a value no longer parses, the previous
+/// snapshot stays in place.
+///
+/// # Examples
+///
+/// ```
+/// # use prefstore::{getpreference, FromPreferences};
+/// # struct Settings { theme: String }
+/// # impl FromPreferences for Settings {
+/// #     fn from_preferences(app_name: &str) -> std::io::Result<Settings> {
+/// #         Ok(Settings { theme: getpreference(app_name, "theme", "light") })
+/// #     }
+/// # }
+/// use prefstore::{savepreference, Live};
+///
+/// let settings: Live<Settings> = Live::new("myapp").unwrap();
+/// savepreference("myapp", "theme", "dark").unwrap();
+/// assert_eq!(settings.get().theme, "dark");
+/// ```
+pub struct Live<T> {
+    app_name: String,
+    current: Shared<T>,
+    _subscription: Subscription,
+    _watcher: Watcher,
+}
+
+impl<T> std::fmt::Debug for Live<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Live").field("app_name", &self.app_name).finish_non_exhaustive()
+    }
+}
+
+fn reload<T: FromPreferences>(app_name: &str, current: &RwLock<Arc<T>>) -> io::Result<()> {
+    let fresh = Arc::new(T::from_preferences(app_name)?);
+    *current.write().unwrap_or_else(|e| e.into_inner()) = fresh;
+    Ok(())
+}
+
+impl<T: FromPreferences + Send + Sync + 'static> Live<T> {
+    /// Loads the preferences of `app_name` and starts following changes to them, watching
+    /// the disk with default [`WatchOptions`].
+    pub fn new(app_name: impl Into<String>) -> io::Result<Live<T>> {
+        Live::with_options(app_name, WatchOptions::default())
+    }
+
+    /// Like [`Live::new`], with explicit options for watching the disk.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the initial load fails or the app's folder cannot be watched.
+    pub fn with_options(app_name: impl Into<String>, options: WatchOptions) -> io::Result<Live<T>> {
+        let app_name = app_name.into();
+        let current: Shared<T> = Arc::new(RwLock::new(Arc::new(T::from_preferences(&app_name)?)));
+
+        let (observed_app, observed) = (app_name.clone(), current.clone());
+        let subscription = observe(app_name.clone(), move |_| {
+            let _ = reload(&observed_app, &observed);
+        });
+        let (watched_app, watched) = (app_name.clone(), current.clone());
+        let watcher = watch_with(app_name.clone(), "", options, move |_| {
+            let _ = reload(&watched_app, &watched);
+        })?;
+
+        Ok(Live { app_name, current, _subscription: subscription, _watcher: watcher })
+    }
+
+    /// Returns the current snapshot.
+    pub fn get(&self) -> Arc<T> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Reloads the snapshot now, returning the error if loading fails.
+    pub fn refresh(&self) -> io::Result<()> {
+        reload(&self.app_name, &self.current)
+    }
+}
+
+#[cfg(test)]
+mod live_test {
+    use super::*;
+    use crate::{ems, getpreference, savecustom, savepreference};
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, PartialEq)]
+    struct Settings {
+        theme: String,
+        font_size: i32,
+    }
+
+    impl FromPreferences for Settings {
+        fn from_preferences(app_name: &str) -> io::Result<Settings> {
+            let font_size = getpreference(app_name, "font_size", 12).toi32()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Settings { theme: getpreference(app_name, "theme", "light"), font_size })
+        }
+    }
+
+    #[test]
+    fn test_live_follows_changes() {
+        let app_name = "prefstore_live_test";
+        let _ = std::fs::remove_dir_all(crate::config_folder_path(app_name).unwrap());
+        let options = WatchOptions { poll_interval: Duration::from_millis(20), force_polling: true, ..Default::default() };
+        let settings: Live<Settings> = Live::with_options(app_name, options).unwrap();
+        let first = settings.get();
+        assert_eq!(*first, Settings { theme: "light".to_string(), font_size: 12 });
+
+        // Writes through the API are visible as soon as they return.
+        savepreference(app_name, "theme", "dark").unwrap();
+        assert_eq!(settings.get().theme, "dark");
+        assert_eq!(first.theme, "light");
+
+        // A value that does not parse keeps the last good snapshot.
+        savepreference(app_name, "font_size", "huge").unwrap();
+        assert_eq!(settings.get().font_size, 12);
+        assert!(settings.refresh().is_err());
+
+        // Changes made outside the API are picked up by the watcher.
+        std::fs::write(crate::config_folder_path(app_name).unwrap().join("font_size.txt"), "14").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while settings.get().font_size != 14 {
+            assert!(Instant::now() < deadline, "external change not picked up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        savecustom(app_name, "font_size.txt", "16").unwrap();
+        assert_eq!(settings.get().font_size, 16);
+    }
+}
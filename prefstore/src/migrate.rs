@@ -0,0 +1,439 @@
+//! Versioned migrations of an app's stored preferences.
+//!
+//! The version an app's preferences are at is kept in a hidden file in its folder.
+//! Migrating runs every migration newer than that version, in order, under a lock so
+//! that only one process migrates at a time. The folder is copied to a hidden backup
+//! first; if a step fails, or the process dies half way, the values of the backup are
+//! written back through the prefstore API, so observers, the audit log and history see
+//! the rollback as well as the steps it undoes.
+
+use std::{fmt, fs::{self, OpenOptions}, io, path::{Path, PathBuf}};
+
+use crate::Store;
+
+pub(crate) const VERSION_FILE: &str = ".prefstore-version";
+const LOCK_FILE: &str = ".prefstore-migration.lock";
+const BACKUP_DIR: &str = ".prefstore-migration-backup";
+const BACKUP_STAGING_DIR: &str = ".prefstore-migration-backup.partial";
+/// Where the backup is moved before it is deleted, so that an interrupted delete never
+/// leaves a partial backup under `BACKUP_DIR` to be restored.
+const BACKUP_TRASH_DIR: &str = ".prefstore-migration-backup.trash";
+
+type Transform = Box<dyn Fn(&str) -> io::Result<String> + Send + Sync>;
+
+enum Step {
+    Rename { from: String, to: String },
+    Transform { key: String, transform: Transform },
+    Delete { key: String },
+}
+
+/// The changes that bring stored preferences to one version, such as renaming or
+/// deleting keys. Keys are preference names, as passed to `savepreference`.
+///
+/// Every step ignores keys that are not stored, so a migration can be written without
+/// knowing which keys an older install actually has.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::Migration;
+///
+/// let migration = Migration::new(2)
+///     .rename("dark_mode", "theme")
+///     .transform("theme", |value| Ok(if value == "true" { "dark" } else { "light" }.to_string()))
+///     .move_into("width", "window")
+///     .delete("last_crash");
+/// ```
+pub struct Migration {
+    version: u32,
+    steps: Vec<Step>,
+    /// Why the migration cannot run, found while it was built.
+    invalid: Option<String>,
+}
+
+impl fmt::Debug for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migration").field("version", &self.version).field("steps", &self.steps.len()).finish()
+    }
+}
+
+impl Migration {
+    /// Starts a migration to `version`, which must be at least 1. Preferences that were
+    /// never migrated are at version 0.
+    pub fn new(version: u32) -> Migration {
+        Migration { version, steps: Vec::new(), invalid: None }
+    }
+
+    /// The version this migration brings preferences to.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Renames the preference `from` to `to`, replacing `to` if it is already stored.
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Migration {
+        self.steps.push(Step::Rename { from: from.into(), to: to.into() });
+        self
+    }
+
+    /// Moves the preference `key` into `namespace`, keeping its name. An empty namespace
+    /// makes migrating fail with `InvalidInput` before any step runs.
+    pub fn move_into(mut self, key: impl Into<String>, namespace: &str) -> Migration {
+        let key = key.into();
+        let namespace = namespace.trim_matches('/');
+        if namespace.is_empty() {
+            self.invalid.get_or_insert(format!("cannot move {:?} into an empty namespace", key));
+            return self;
+        }
+        let to = format!("{}/{}", namespace, key);
+        self.rename(key, to)
+    }
+
+    /// Replaces the value of the preference `key` with what `transform` returns for it.
+    /// An error returned by `transform` fails the migration.
+    pub fn transform<F>(mut self, key: impl Into<String>, transform: F) -> Migration
+    where F: Fn(&str) -> io::Result<String> + Send + Sync + 'static {
+        self.steps.push(Step::Transform { key: key.into(), transform: Box::new(transform) });
+        self
+    }
+
+    /// Deletes the preference `key`.
+    pub fn delete(mut self, key: impl Into<String>) -> Migration {
+        self.steps.push(Step::Delete { key: key.into() });
+        self
+    }
+}
+
+/// The ordered list of an app's migrations, run by [`Store::open`] or [`Store::migrate`].
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{Migration, Migrations, Store};
+///
+/// let migrations = Migrations::new()
+///     .migration(Migration::new(1).rename("colour", "color"))
+///     .migration(Migration::new(2).move_into("color", "ui"));
+/// let store = Store::open("myapp", &migrations).unwrap();
+/// assert_eq!(store.schema_version().unwrap(), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct Migrations {
+    migrations: Vec<Migration>,
+}
+
+impl Migrations {
+    /// Creates an empty list.
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Adds a migration. Migrations run in order of their versions, whatever order they
+    /// are added in.
+    pub fn migration(mut self, migration: Migration) -> Migrations {
+        self.migrations.push(migration);
+        self
+    }
+
+    /// The highest version of the migrations, or 0 if there are none.
+    pub fn latest_version(&self) -> u32 {
+        self.migrations.iter().map(|migration| migration.version).max().unwrap_or(0)
+    }
+
+    /// The migrations sorted by version.
+    fn sorted(&self) -> io::Result<Vec<&Migration>> {
+        let mut sorted: Vec<&Migration> = self.migrations.iter().collect();
+        sorted.sort_by_key(|migration| migration.version);
+        if sorted.first().is_some_and(|migration| migration.version == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "migration versions start at 1"));
+        }
+        if let Some(pair) = sorted.windows(2).find(|pair| pair[0].version == pair[1].version) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("two migrations to version {}", pair[0].version)));
+        }
+        if let Some((version, invalid)) = sorted.iter().find_map(|migration| Some((migration.version, migration.invalid.as_ref()?))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("migration to version {}: {}", version, invalid)));
+        }
+        Ok(sorted)
+    }
+}
+
+impl Store {
+    /// Creates a handle on the preferences of the given app, first running the migrations
+    /// it has not run yet. See [`Store::migrate`].
+    pub fn open(app_name: impl Into<String>, migrations: &Migrations) -> io::Result<Store> {
+        let store = Store::new(app_name)?;
+        store.migrate(migrations)?;
+        Ok(store)
+    }
+
+    /// The version the app's preferences were last migrated to, or 0 if they never were.
+    pub fn schema_version(&self) -> io::Result<u32> {
+        match fs::read_to_string(self.dir().join(VERSION_FILE)) {
+            Ok(text) => text.trim().parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("invalid schema version {:?}", text))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs, in order, every migration newer than the stored version, then records the
+    /// latest version. Returns the version the preferences are at afterwards. Preferences
+    /// at a newer version than any migration, for example after a downgrade, are left
+    /// alone.
+    ///
+    /// # Errors
+    ///
+    /// Fails if two migrations have the same version or one has version 0, if one moves a
+    /// key into an empty namespace, or if a step fails. When a step fails, the preferences are restored to how they were before
+    /// migrating, including the stored version.
+    pub fn migrate(&self, migrations: &Migrations) -> io::Result<u32> {
+        let migrations = migrations.sorted()?;
+        crate::permissions::create_dirs(self.dir())?;
+        // Held until this returns, so that only one process migrates at a time.
+        let lock = crate::permissions::open(&self.dir().join(LOCK_FILE), OpenOptions::new().create(true).truncate(false).write(true))?;
+        lock.lock()?;
+
+        let backup = self.dir().join(BACKUP_DIR);
+        if backup.exists() {
+            // A migration was interrupted; undo whatever part of it ran.
+            restore(self.app_name(), &backup)?;
+        }
+        remove_dir_if_exists(&self.dir().join(BACKUP_STAGING_DIR))?;
+        remove_dir_if_exists(&self.dir().join(BACKUP_TRASH_DIR))?;
+
+        let current = self.schema_version()?;
+        let pending: Vec<&Migration> = migrations.into_iter().filter(|migration| migration.version > current).collect();
+        if pending.is_empty() {
+            return Ok(current);
+        }
+
+        let latest = pending.last().map_or(current, |migration| migration.version);
+        crate::snapshot::snapshot_before(self.app_name(), &format!("migration to version {}", latest))?;
+        let staging = self.dir().join(BACKUP_STAGING_DIR);
+        copy_tree(self.dir(), &staging)?;
+        fs::rename(&staging, &backup)?;
+
+        for migration in pending {
+            if let Err(e) = self.run(migration) {
+                let error = io::Error::new(e.kind(), format!("migration to version {} failed: {}", migration.version, e));
+                return match restore(self.app_name(), &backup) {
+                    Ok(()) => Err(error),
+                    Err(restore_error) => Err(io::Error::new(
+                        restore_error.kind(),
+                        format!("{}; restoring the backup also failed: {}", error, restore_error),
+                    )),
+                };
+            }
+        }
+        discard_backup(&backup)?;
+        self.schema_version()
+    }
+
+    fn run(&self, migration: &Migration) -> io::Result<()> {
+        for step in &migration.steps {
+            match step {
+                Step::Rename { from, to } => self.rename_preference(from, to)?,
+                Step::Transform { key, transform } => {
+                    let path = self.preference_path(key)?;
+                    let old = match fs::read_to_string(&path) {
+                        Ok(old) => old,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let new = transform(&old)?;
+                    if new != old {
+                        crate::tracked_write(self.app_name(), &path, || crate::atomic_write(&path, new.as_bytes()))?;
+                    }
+                },
+                Step::Delete { key } => {
+                    let path = self.preference_path(key)?;
+                    ignore_not_found(crate::tracked_write(self.app_name(), &path, || fs::remove_file(&path)))?;
+                },
+            }
+        }
+        let version = self.dir().join(VERSION_FILE);
+        crate::atomic_write(&version, migration.version.to_string().as_bytes())
+    }
+
+    fn rename_preference(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (self.preference_path(from)?, self.preference_path(to)?);
+        if from == to {
+            return Ok(());
+        }
+        let value = match fs::read(&from) {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if let Some(parent) = to.parent() {
+            crate::permissions::create_dirs(parent)?;
+        }
+        crate::tracked_write(self.app_name(), &to, || crate::atomic_write(&to, &value))?;
+        crate::tracked_write(self.app_name(), &from, || fs::remove_file(&from))
+    }
+
+    fn preference_path(&self, key: &str) -> io::Result<PathBuf> {
+        crate::namespace_path(self.app_name(), &format!("{}.txt", key))
+    }
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn remove_dir_if_exists(dir: &Path) -> io::Result<()> {
+    ignore_not_found(fs::remove_dir_all(dir))
+}
+
+/// Whether a folder entry belongs to the migration machinery or the audit log rather than
+/// the app. The audit log keeps the steps of a migration that was rolled back.
+fn is_internal(name: &std::ffi::OsStr) -> bool {
+    name == LOCK_FILE || name == BACKUP_DIR || name == BACKUP_STAGING_DIR || name == BACKUP_TRASH_DIR || crate::audit::is_audit_file(name)
+}
+
+/// Copies the app's folder to `to`, leaving out the migration machinery and symbolic links.
+pub(crate) fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    copy_tree_with(from, to, &|source, target, _| fs::copy(source, target).map(|_| ()))
+}
+
+/// Like `copy_tree`, with `copy` called for every file with its source, its target and
+/// its path relative to `from`.
+pub(crate) fn copy_tree_with(from: &Path, to: &Path, copy: &dyn Fn(&Path, &Path, &Path) -> io::Result<()>) -> io::Result<()> {
+    fn walk(from: &Path, to: &Path, relative: &Path, copy: &dyn Fn(&Path, &Path, &Path) -> io::Result<()>) -> io::Result<()> {
+        crate::permissions::create_dirs(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if is_internal(&entry.file_name()) || file_type.is_symlink() {
+                continue;
+            }
+            let (target, relative) = (to.join(entry.file_name()), relative.join(entry.file_name()));
+            if file_type.is_dir() {
+                walk(&entry.path(), &target, &relative, copy)?;
+            } else {
+                copy(&entry.path(), &target, &relative)?;
+            }
+        }
+        Ok(())
+    }
+    walk(from, to, Path::new(""), copy)
+}
+
+/// Brings the app's values back to those of the backup, then removes the backup.
+fn restore(app_name: &str, backup: &Path) -> io::Result<()> {
+    crate::snapshot::restore_values(app_name, backup)?;
+    discard_backup(backup)
+}
+
+/// Deletes the backup. It is first renamed out of the way in one step, so that it is
+/// either complete or absent under its own name.
+fn discard_backup(backup: &Path) -> io::Result<()> {
+    let trash = backup.with_file_name(BACKUP_TRASH_DIR);
+    remove_dir_if_exists(&trash)?;
+    fs::rename(backup, &trash)?;
+    fs::remove_dir_all(&trash)
+}
+
+#[cfg(test)]
+mod migrate_test {
+    use super::*;
+    use crate::{getpreference, savepreference};
+
+    fn migrations() -> Migrations {
+        Migrations::new()
+            .migration(Migration::new(2)
+                .transform("theme", |value| Ok(format!("{}!", value)))
+                .move_into("width", "window")
+                .delete("last_crash"))
+            .migration(Migration::new(1).rename("dark_mode", "theme"))
+    }
+
+    fn fresh(app_name: &str) -> Store {
+        let store = Store::new(app_name).unwrap();
+        let _ = fs::remove_dir_all(store.dir());
+        savepreference(app_name, "dark_mode", "dark").unwrap();
+        savepreference(app_name, "width", 800).unwrap();
+        savepreference(app_name, "last_crash", "yesterday").unwrap();
+        store
+    }
+
+    #[test]
+    fn test_migrations_run_once() {
+        let app_name = "prefstore_migrate_test";
+        fresh(app_name);
+        let store = Store::open(app_name, &migrations()).unwrap();
+        assert_eq!(store.schema_version().unwrap(), 2);
+        assert_eq!(getpreference(app_name, "theme", ""), "dark!");
+        assert_eq!(getpreference(app_name, "window/width", ""), "800");
+        assert!(!store.dir().join("dark_mode.txt").exists());
+        assert!(!store.dir().join("width.txt").exists());
+        assert!(!store.dir().join("last_crash.txt").exists());
+
+        // Opening again does not run the migrations a second time.
+        assert_eq!(store.migrate(&migrations()).unwrap(), 2);
+        assert_eq!(getpreference(app_name, "theme", ""), "dark!");
+        assert!(!store.dir().join(BACKUP_DIR).exists());
+        assert_eq!(crate::list_keys(app_name, "").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        let app_name = "prefstore_migrate_rollback_test";
+        let store = fresh(app_name);
+        let failing = migrations().migration(Migration::new(3)
+            .rename("theme", "ui/theme")
+            .transform("window/width", |_| Err(io::Error::new(io::ErrorKind::InvalidData, "bad width"))));
+
+        let error = store.migrate(&failing).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("version 3"));
+        assert_eq!(store.schema_version().unwrap(), 0);
+        assert_eq!(getpreference(app_name, "dark_mode", ""), "dark");
+        assert_eq!(getpreference(app_name, "width", ""), "800");
+        assert!(!store.dir().join("theme.txt").exists());
+        assert!(!store.dir().join("window").exists());
+        assert!(!store.dir().join(BACKUP_DIR).exists());
+
+        // The rollback is reported like the steps it undoes.
+        let (_subscription, events) = crate::observe_channel(app_name);
+        store.migrate(&failing).unwrap_err();
+        let keys: Vec<String> = events.try_iter().map(|event| event.key).collect();
+        assert!(keys.ends_with(&["dark_mode.txt", "last_crash.txt", "width.txt", "ui/theme.txt", "window/width.txt"].map(String::from)), "{:?}", keys);
+
+        assert_eq!(store.migrate(&migrations()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_partially_deleted_backup_is_not_restored() {
+        let app_name = "prefstore_migrate_trash_test";
+        let store = fresh(app_name);
+        assert_eq!(store.migrate(&migrations()).unwrap(), 2);
+
+        // A process died while deleting the backup of that migration, leaving part of it.
+        let trash = store.dir().join(BACKUP_TRASH_DIR);
+        fs::create_dir_all(&trash).unwrap();
+        fs::write(trash.join("dark_mode.txt"), "dark").unwrap();
+
+        assert_eq!(store.migrate(&migrations()).unwrap(), 2);
+        assert_eq!(getpreference(app_name, "theme", ""), "dark!");
+        assert_eq!(getpreference(app_name, "window/width", ""), "800");
+        assert!(!store.dir().join("dark_mode.txt").exists());
+        assert!(!trash.exists());
+    }
+
+    #[test]
+    fn test_invalid_versions() {
+        let store = Store::new("prefstore_migrate_invalid_test").unwrap();
+        let duplicate = Migrations::new().migration(Migration::new(1)).migration(Migration::new(1));
+        assert_eq!(store.migrate(&duplicate).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let zero = Migrations::new().migration(Migration::new(0));
+        assert_eq!(store.migrate(&zero).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let nowhere = Migrations::new().migration(Migration::new(1).move_into("width", "/"));
+        let error = store.migrate(&nowhere).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("empty namespace"), "{}", error);
+    }
+}
@@ -0,0 +1,167 @@
+//! In-process observers of writes made through the prefstore API.
+//!
+//! Observers are registered per app in a global registry. Every function that changes a
+//! stored value reports the key with its old and new value to the observers of that app
+//! on the writing thread, right after the write. When an app has no observers the old and
+//! new values are never read, so writes cost nothing extra.
+
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}, mpsc::{channel, Receiver}}};
+
+type Callback = Arc<dyn Fn(&WriteEvent) + Send + Sync>;
+
+struct Observer {
+    id: u64,
+    app_name: String,
+    callback: Callback,
+}
+
+static OBSERVERS: Mutex<Vec<Observer>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A change made through the prefstore API, reported to observers.
+///
+/// The values of keys marked with [`mark_sensitive`](crate::mark_sensitive) are reported
+/// as [`REDACTED`](crate::REDACTED); read the key itself to get its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteEvent {
+    /// The app the key belongs to.
+    pub app_name: String,
+    /// The key that changed, relative to the app's folder, such as `"theme.txt"`.
+    pub key: String,
+    /// The value before the write, or `None` if the key did not exist.
+    pub old: Option<String>,
+    /// The value after the write, or `None` if the key was removed.
+    pub new: Option<String>,
+}
+
+/// Keeps an observer registered. Dropping it, or calling [`Subscription::unsubscribe`],
+/// removes the observer.
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+}
+
+impl Subscription {
+    /// Removes the observer. It is not called again once this returns, except by writes
+    /// already in progress on other threads.
+    pub fn unsubscribe(self) {}
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut observers) = OBSERVERS.lock() {
+            observers.retain(|observer| observer.id != self.id);
+        }
+    }
+}
+
+/// Calls `callback` for every change made through the prefstore API to the given app's
+/// preferences, such as `savepreference`, `clearpreference` or `savebuffer`.
+///
+/// The callback runs on the thread that made the write, after the write completed. It
+/// may itself read or write preferences.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{observe, savepreference};
+///
+/// let subscription = observe("myapp", |event| {
+///     println!("{} changed from {:?} to {:?}", event.key, event.old, event.new);
+/// });
+/// savepreference("myapp", "theme", "dark").unwrap();
+/// drop(subscription);
+/// ```
+pub fn observe<F>(app_name: impl Into<String>, callback: F) -> Subscription
+where F: Fn(&WriteEvent) + Send + Sync + 'static {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let observer = Observer { id, app_name: app_name.into(), callback: Arc::new(callback) };
+    OBSERVERS.lock().unwrap_or_else(|e| e.into_inner()).push(observer);
+    Subscription { id }
+}
+
+/// Like [`observe`], but delivers the changes through a channel, for example to a
+/// background worker. Changes are dropped once the receiver is gone.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{observe_channel, savepreference};
+///
+/// let (_subscription, events) = observe_channel("myapp");
+/// savepreference("myapp", "volume", 7).unwrap();
+/// let event = events.recv().unwrap();
+/// assert_eq!(event.key, "volume.txt");
+/// ```
+pub fn observe_channel(app_name: impl Into<String>) -> (Subscription, Receiver<WriteEvent>) {
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let subscription = observe(app_name, move |event| {
+        if let Ok(sender) = sender.lock() {
+            let _ = sender.send(event.clone());
+        }
+    });
+    (subscription, receiver)
+}
+
+/// Returns `true` if anything observes the given app's writes.
+pub(crate) fn has_observers(app_name: &str) -> bool {
+    OBSERVERS.lock().map(|observers| observers.iter().any(|observer| observer.app_name == app_name)).unwrap_or(false)
+}
+
+/// Reports a write to the app's observers. The registry is not locked while callbacks
+/// run, so they may subscribe, unsubscribe or write themselves.
+pub(crate) fn notify(mut event: WriteEvent) {
+    if crate::is_sensitive(&event.app_name, &event.key) {
+        event.old = crate::sensitive::redact_option(&event.app_name, &event.key, event.old);
+        event.new = crate::sensitive::redact_option(&event.app_name, &event.key, event.new);
+    }
+    let callbacks: Vec<Callback> = match OBSERVERS.lock() {
+        Ok(observers) => observers.iter()
+            .filter(|observer| observer.app_name == event.app_name)
+            .map(|observer| observer.callback.clone())
+            .collect(),
+        Err(_) => return,
+    };
+    for callback in callbacks {
+        callback(&event);
+    }
+}
+
+#[cfg(test)]
+mod observe_test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_observers_see_every_write() {
+        let app_name = "prefstore_observe_test";
+        clearall(app_name, "txt").unwrap();
+        clearcustom(app_name, "recent");
+        let (subscription, events) = observe_channel(app_name);
+
+        savepreference(app_name, "theme", "dark").unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        appendcustomnewline(app_name, "log.txt", "started").unwrap();
+        savebuffer(app_name, "recent", "a", 2).unwrap();
+        clearpreference(app_name, "theme").unwrap();
+        clearall(app_name, "txt").unwrap();
+        savepreference("prefstore_observe_other", "theme", "dark").unwrap();
+
+        let events: Vec<(String, Option<String>, Option<String>)> = events.try_iter()
+            .map(|event| (event.key, event.old, event.new))
+            .collect();
+        assert_eq!(events, vec![
+            ("theme.txt".to_string(), None, Some("dark".to_string())),
+            ("theme.txt".to_string(), Some("dark".to_string()), Some("light".to_string())),
+            ("log.txt".to_string(), None, Some("started\n".to_string())),
+            ("recent".to_string(), None, Some("a".to_string())),
+            ("theme.txt".to_string(), Some("light".to_string()), None),
+            ("log.txt".to_string(), Some("started\n".to_string()), None),
+        ]);
+
+        subscription.unsubscribe();
+        assert!(!has_observers(app_name));
+    }
+}
@@ -0,0 +1,333 @@
+//! File and directory modes of an app's preferences, and safe creation of its files.
+//!
+//! Every file and directory prefstore creates in an app's folder goes through here.
+//! Files are opened without following symbolic links, and no directory below the app's
+//! folder may be a symbolic link, so a link planted in the folder cannot redirect a write
+//! elsewhere. Modes registered with [`set_file_modes`] are applied to everything created
+//! or rewritten in the folder; without them files are only readable by their owner and
+//! directories only enterable by their owner, as with [`FileModes::private`].
+//!
+//! Modes only have an effect on Unix. Elsewhere they are accepted and ignored.
+
+use std::{fs::{self, File, OpenOptions}, io, path::{Component, Path, PathBuf}, sync::Mutex};
+
+static MODES: Mutex<Vec<(PathBuf, FileModes)>> = Mutex::new(Vec::new());
+
+/// Unix permission bits for the files and directories of an app, with optional
+/// per-key overrides.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{set_file_modes, FileModes};
+///
+/// let modes = FileModes::new(0o640, 0o750).key("secrets/**", 0o600).unwrap();
+/// set_file_modes("myapp", modes).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileModes {
+    file: u32,
+    dir: u32,
+    keys: Vec<(glob::Pattern, u32)>,
+}
+
+impl FileModes {
+    /// Creates files with `file_mode` and directories with `dir_mode`, such as `0o644`
+    /// and `0o755`.
+    pub fn new(file_mode: u32, dir_mode: u32) -> FileModes {
+        FileModes { file: file_mode & 0o7777, dir: dir_mode & 0o7777, keys: Vec::new() }
+    }
+
+    /// Files readable only by their owner (`0o600`) in directories only the owner can
+    /// enter (`0o700`).
+    pub fn private() -> FileModes {
+        FileModes::new(0o600, 0o700)
+    }
+
+    /// Uses `file_mode` for the keys matching a glob pattern such as `"token.txt"` or
+    /// `"secrets/**"`. `*` does not match `/`. The first matching pattern wins.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pattern is not a valid glob.
+    pub fn key(mut self, pattern: &str, file_mode: u32) -> io::Result<FileModes> {
+        let pattern = glob::Pattern::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.keys.push((pattern, file_mode & 0o7777));
+        Ok(self)
+    }
+
+    /// The mode of the file storing `key`, a path relative to the app's folder.
+    pub fn file_mode(&self, key: &str) -> u32 {
+        let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+        self.keys.iter()
+            .find(|(pattern, _)| pattern.matches_with(key, options))
+            .map_or(self.file, |(_, mode)| *mode)
+    }
+
+    /// The mode of directories.
+    pub fn dir_mode(&self) -> u32 {
+        self.dir
+    }
+}
+
+/// Sets the modes of the files and directories prefstore creates or rewrites for the app
+/// from now on, replacing modes set before. Existing files keep their modes until they are
+/// written; use [`check_permissions`] to find them.
+pub fn set_file_modes(app_name: impl Into<String>, modes: FileModes) -> io::Result<()> {
+    let dir = crate::config_folder_path(&app_name.into())?;
+    let mut registry = MODES.lock().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|(registered, _)| *registered != dir);
+    registry.push((dir, modes));
+    Ok(())
+}
+
+/// A file or directory of an app that is more permissive than it should be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionWarning {
+    /// The path relative to the app's folder, or `""` for the folder itself.
+    pub key: String,
+    /// The full path.
+    pub path: PathBuf,
+    /// The permission bits found.
+    pub mode: u32,
+    /// The most permissive mode allowed.
+    pub expected: u32,
+    /// `true` if the entry is a symbolic link, which prefstore refuses to follow.
+    pub symlink: bool,
+}
+
+/// Lists the files and directories of an app that allow more than the modes set with
+/// [`set_file_modes`], or that group or others can access at all if no modes were set, as
+/// well as any symbolic links. Modes are only checked on Unix.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::check_permissions;
+///
+/// for warning in check_permissions("myapp").unwrap() {
+///     eprintln!("{:?} has mode {:o}, expected at most {:o}", warning.path, warning.mode, warning.expected);
+/// }
+/// ```
+pub fn check_permissions(app_name: impl Into<String>) -> io::Result<Vec<PermissionWarning>> {
+    let app_dir = crate::config_folder_path(&app_name.into())?;
+    let modes = modes_for(&app_dir);
+    let mut warnings = Vec::new();
+    let mut pending = vec![app_dir.clone()];
+    while let Some(path) = pending.pop() {
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let key = if path == app_dir { String::new() } else { crate::relative_key(&app_dir, &path)? };
+        let symlink = metadata.file_type().is_symlink() && path != app_dir;
+        let expected = if metadata.is_dir() { modes.dir_mode() } else { modes.file_mode(&key) };
+        let mode = mode_of(&metadata);
+        if symlink || mode.is_some_and(|mode| mode & !expected != 0) {
+            warnings.push(PermissionWarning { key, path: path.clone(), mode: mode.unwrap_or(0), expected, symlink });
+        }
+        if metadata.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+        }
+    }
+    warnings.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(warnings)
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// The modes registered for the app folder `path` is in, or private modes if none were.
+fn modes_for(path: &Path) -> FileModes {
+    let registry = MODES.lock().unwrap_or_else(|e| e.into_inner());
+    registry.iter().find(|(dir, _)| path.starts_with(dir)).map_or_else(FileModes::private, |(_, modes)| modes.clone())
+}
+
+/// The app folder `path` is in, if it is inside the system configuration directory.
+fn app_dir_of(path: &Path) -> Option<PathBuf> {
+    let config_dir = dirs::config_dir()?;
+    let app = path.strip_prefix(&config_dir).ok()?.components().next()?;
+    Some(config_dir.join(app))
+}
+
+fn symlink_error(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("refusing to follow the symbolic link {:?}", path))
+}
+
+#[cfg(unix)]
+fn set_mode(path_or_file: impl FnOnce(fs::Permissions) -> io::Result<()>, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    path_or_file(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path_or_file: impl FnOnce(fs::Permissions) -> io::Result<()>, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates `dir` and its missing parents. Below the app's folder, existing directories
+/// must not be symbolic links, and new ones get the app's directory mode.
+pub(crate) fn create_dirs(dir: &Path) -> io::Result<()> {
+    let app_dir = match app_dir_of(dir) {
+        Some(app_dir) => app_dir,
+        None => return fs::create_dir_all(dir),
+    };
+    let modes = modes_for(&app_dir);
+    let create = |path: &Path, all: bool| -> io::Result<()> {
+        let created = if all { fs::create_dir_all(path) } else { fs::create_dir(path) };
+        match created {
+            Ok(()) => set_mode(|permissions| fs::set_permissions(path, permissions), modes.dir_mode()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    };
+    if !app_dir.is_dir() {
+        create(&app_dir, true)?;
+    }
+    let mut current = app_dir.clone();
+    for component in dir.strip_prefix(&app_dir).unwrap_or(Path::new("")).components() {
+        match component {
+            Component::Normal(name) => current.push(name),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid path {:?}", dir))),
+        }
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Err(symlink_error(&current)),
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::NotFound => create(&current, false)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Opens `path` with `options` without following a symbolic link at `path`, then applies
+/// the mode of its app, if it is in an app's folder.
+pub(crate) fn open(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    open_for(path, path, options)
+}
+
+/// Like `open`, applying the mode of `target`, the file that `path` will be renamed to.
+/// Outside an app's folder, the mode of an existing `target` is kept.
+pub(crate) fn open_for(path: &Path, target: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(options, libc::O_NOFOLLOW);
+    let file = options.open(path).map_err(|e| {
+        #[cfg(unix)]
+        if e.raw_os_error() == Some(libc::ELOOP) {
+            return symlink_error(path);
+        }
+        e
+    })?;
+    match file_mode(target) {
+        Some(mode) => set_mode(|permissions| file.set_permissions(permissions), mode)?,
+        None if target != path => {
+            if let Some(metadata) = fs::symlink_metadata(target).ok().filter(|metadata| metadata.is_file()) {
+                file.set_permissions(metadata.permissions())?;
+            }
+        },
+        None => {},
+    }
+    Ok(file)
+}
+
+/// The mode of the file at `path`, if it is in an app's folder.
+pub(crate) fn file_mode(path: &Path) -> Option<u32> {
+    let app_dir = app_dir_of(path)?;
+    let modes = modes_for(&app_dir);
+    Some(modes.file_mode(&crate::relative_key(&app_dir, path).ok()?))
+}
+
+#[cfg(all(test, unix))]
+mod permissions_test {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_modes_are_applied() {
+        let app_name = "prefstore_permissions_test";
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        set_file_modes(app_name, FileModes::new(0o640, 0o750).key("secrets/*", 0o600).unwrap()).unwrap();
+
+        crate::savepreference(app_name, "theme", "dark").unwrap();
+        crate::savecustom(app_name, "secrets/token.txt", "abc").unwrap();
+        crate::appendcustomnewline(app_name, "log.txt", "started").unwrap();
+        crate::savebuffer(app_name, "recent", "a", 3).unwrap();
+        assert_eq!(mode(&dir), 0o750);
+        assert_eq!(mode(&dir.join("theme.txt")), 0o640);
+        assert_eq!(mode(&dir.join("secrets")), 0o750);
+        assert_eq!(mode(&dir.join("secrets/token.txt")), 0o600);
+        assert_eq!(mode(&dir.join("log.txt")), 0o640);
+        assert_eq!(mode(&dir.join("recent")), 0o640);
+        assert!(check_permissions(app_name).unwrap().is_empty());
+
+        fs::set_permissions(dir.join("secrets/token.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        let warnings = check_permissions(app_name).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].key.as_str(), warnings[0].mode, warnings[0].expected), ("secrets/token.txt", 0o644, 0o600));
+
+        // Rewriting the file restores its mode.
+        crate::savecustom(app_name, "secrets/token.txt", "def").unwrap();
+        assert_eq!(mode(&dir.join("secrets/token.txt")), 0o600);
+    }
+
+    #[test]
+    fn test_default_modes_are_private() {
+        let app_name = "prefstore_permissions_default_test";
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        crate::savecustom(app_name, "window/width.txt", "800").unwrap();
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&dir.join("window")), 0o700);
+        assert_eq!(mode(&dir.join("window/width.txt")), 0o600);
+        assert!(check_permissions(app_name).unwrap().is_empty());
+
+        fs::set_permissions(dir.join("window/width.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        let warnings = check_permissions(app_name).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].mode, warnings[0].expected), (0o644, 0o600));
+    }
+
+    #[test]
+    fn test_symlinks_are_not_followed() {
+        let app_name = "prefstore_permissions_symlink_test";
+        let dir = crate::config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let outside = std::env::temp_dir().join("prefstore_permissions_symlink_target");
+        let _ = fs::remove_dir_all(&outside);
+        fs::create_dir_all(outside.join("ns")).unwrap();
+        fs::write(outside.join("log.txt"), "untouched").unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        symlink(outside.join("log.txt"), dir.join("log.txt")).unwrap();
+        symlink(outside.join("ns"), dir.join("ns")).unwrap();
+
+        let error = crate::appendcustom(app_name, "log.txt", "appended").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(crate::savecustom(app_name, "ns/key.txt", "value").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_to_string(outside.join("log.txt")).unwrap(), "untouched");
+        assert!(!outside.join("ns/key.txt").exists());
+
+        let warnings = check_permissions(app_name).unwrap();
+        assert_eq!(warnings.iter().filter(|warning| warning.symlink).count(), 2);
+
+        // Replacing a value swaps out the link itself rather than writing through it.
+        crate::savecustom(app_name, "log.txt", "replaced").unwrap();
+        assert!(!fs::symlink_metadata(dir.join("log.txt")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(outside.join("log.txt")).unwrap(), "untouched");
+    }
+}
@@ -0,0 +1,129 @@
+//! Typed preference structs, usually implemented with `#[derive(Preferences)]`.
+
+use std::{fmt::Display, fs, io, str::FromStr};
+
+/// A struct whose fields are each stored as a preference of an app.
+///
+/// With the `derive` feature this trait can be derived. Every field becomes the
+/// preference named after it, and its type must implement `ToString` and `FromStr`. The
+/// derive also implements [`FromPreferences`](crate::FromPreferences), so the struct can
+/// be used with [`Live`](crate::Live), and adds `get_<field>` and `set_<field>`
+/// functions that read or write a single field.
+///
+/// Fields accept these attributes:
+///
+/// * `#[pref(key = "name")]` stores the field under another key.
+/// * `#[pref(default = "value")]` is used when the key is not stored. The literal is
+///   parsed with `FromStr`. Without it, `Default::default()` is used.
+/// * `#[pref(namespace = "dir")]` stores the field inside a namespace. It can also be put
+///   on the struct to apply to every field.
+/// * `#[pref(sensitive)]` marks the field's key sensitive, see
+///   [`mark_sensitive`](crate::mark_sensitive), whenever the field is loaded or saved, so
+///   listings mask its value. The value is still stored as plain text; use
+///   [`save_secret`](crate::save_secret) to encrypt it.
+///
+/// # Examples
+///
+/// ```ignore
+/// use prefstore::Preferences;
+///
+/// #[derive(Preferences)]
+/// struct Settings {
+///     #[pref(default = "light")]
+///     theme: String,
+///     #[pref(key = "font", default = 12)]
+///     font_size: i32,
+///     #[pref(namespace = "sync", sensitive)]
+///     token: String,
+/// }
+///
+/// let mut settings = Settings::load("myapp")?;
+/// settings.theme = "dark".to_string();
+/// settings.save("myapp")?;
+/// Settings::set_font_size("myapp", &14)?;
+/// ```
+pub trait Preferences: Sized {
+    /// Reads every field, using its default when the key is not stored.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a stored value cannot be read or parsed.
+    fn load(app_name: &str) -> io::Result<Self>;
+
+    /// Stores every field.
+    fn save(&self, app_name: &str) -> io::Result<()>;
+
+    /// The keys the fields are stored under, in field order.
+    fn keys() -> &'static [&'static str];
+
+    /// The keys of the fields marked `sensitive`.
+    fn sensitive_keys() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Marks the keys of the fields marked `sensitive` as sensitive for the app, so that
+    /// listings such as `getall` mask their values before the struct is first loaded or
+    /// saved. See [`mark_sensitive`](crate::mark_sensitive).
+    fn mark_sensitive_keys(app_name: &str) -> io::Result<()> {
+        for key in Self::sensitive_keys() {
+            mark_key_sensitive(app_name, key)?;
+        }
+        Ok(())
+    }
+}
+
+fn mark_key_sensitive(app_name: &str, key: &str) -> io::Result<()> {
+    crate::mark_sensitive(app_name, &glob::Pattern::escape(key))
+}
+
+/// Reads and parses the preference `key`, or calls `default` if it is not stored.
+pub(crate) fn read_typed<T: FromStr>(app_name: &str, key: &str, default: impl FnOnce() -> io::Result<T>) -> io::Result<T>
+where T::Err: Display {
+    let key = &crate::alias::resolve(app_name, key);
+    let path = crate::customfile_path(&app_name.to_string(), format!("{}.txt", key))?;
+    let stored = match crate::alias::fallback(app_name, key) {
+        Ok(Some(value)) => Ok(value),
+        Err(e) => Err(e),
+        Ok(None) => fs::read_to_string(&path)
+            .and_then(|text| crate::verify_integrity(&path, text.as_bytes()).map(|_| text)),
+    };
+    match stored {
+        Ok(text) => text.parse::<T>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("could not parse preference {:?} from {:?}: {}", key, text, e))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => default(),
+        Err(e) => Err(e),
+    }
+}
+
+/// Support code for the code generated by `#[derive(Preferences)]`. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    use super::*;
+
+    /// Reads the preference `key`, or calls `default` if it is not stored. A `sensitive`
+    /// key is marked sensitive first.
+    pub fn load_field<T: FromStr>(app_name: &str, key: &str, sensitive: bool, default: impl FnOnce() -> io::Result<T>) -> io::Result<T>
+    where T::Err: Display {
+        if sensitive {
+            mark_key_sensitive(app_name, key)?;
+        }
+        read_typed(app_name, key, default)
+    }
+
+    /// Stores `value` as the preference `key`. A `sensitive` key is marked sensitive first.
+    pub fn save_field<T: ToString>(app_name: &str, key: &str, sensitive: bool, value: &T) -> io::Result<()> {
+        if sensitive {
+            mark_key_sensitive(app_name, key)?;
+        }
+        crate::savepreference(app_name, key, value.to_string())
+    }
+
+    /// Parses a default given in a `#[pref(default = ...)]` attribute.
+    pub fn parse_default<T: FromStr>(key: &str, text: &str) -> io::Result<T>
+    where T::Err: Display {
+        text.parse::<T>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid default {:?} for preference {:?}: {}", text, key, e))
+        })
+    }
+}
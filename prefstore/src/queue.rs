@@ -0,0 +1,304 @@
+//! Persistent FIFO work queue with acknowledgements.
+//!
+//! Every queue is a directory inside the app's config folder. Each item is its own file
+//! named after a sequence number, so enqueueing and acknowledging never rewrite other
+//! items. Dequeueing renames the oldest item to a lease file; acknowledging deletes the
+//! lease and nacking renames it back. Leases that are not settled within the lease
+//! timeout, for example because the consumer crashed, are handed out again.
+//!
+//! All operations take an exclusive lock on the queue directory, so a queue can be
+//! shared between threads and processes.
+//!
+//! Items are work in flight rather than stored values. Queue operations write the files
+//! directly, so they are not reported to observers, the audit log or key history, and a
+//! hidden marker file keeps the queue directory out of snapshots and bundles.
+
+use std::{fs::{self, File, OpenOptions, remove_file, rename}, io, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+const LOCK_FILE: &str = ".lock";
+const MARKER_FILE: &str = ".prefstore-queue";
+const SEQ_FILE: &str = ".seq";
+const ITEM_EXT: &str = "item";
+const LEASE_EXT: &str = "lease";
+const DEFAULT_LEASE_TIMEOUT: Duration = Duration::from_secs(300);
+
+static LEASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A durable first-in first-out queue stored under an app's config folder.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::Queue;
+///
+/// let queue = Queue::open("myapp", "jobs").unwrap();
+/// queue.enqueue("resize photo.jpg").unwrap();
+/// if let Some(lease) = queue.dequeue().unwrap() {
+///     println!("working on {}", lease.value);
+///     queue.ack(lease).unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Queue {
+    dir: PathBuf,
+    lease_timeout: Duration,
+}
+
+/// An item handed out by [`Queue::dequeue`]. It must be passed back to [`Queue::ack`]
+/// once processed, or to [`Queue::nack`] to put it back at the front of the queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    /// The sequence number of the item, unique within its queue.
+    pub id: u64,
+    /// The value that was enqueued.
+    pub value: String,
+    token: String,
+}
+
+/// An item file name split into its sequence number and, for leases, the lease token.
+fn parse_name(name: &str) -> Option<(u64, Option<&str>)> {
+    let mut parts = name.split('.');
+    let seq = parts.next()?.parse().ok()?;
+    match (parts.next()?, parts.next(), parts.next()) {
+        (ITEM_EXT, None, None) => Some((seq, None)),
+        (token, Some(LEASE_EXT), None) => Some((seq, Some(token))),
+        _ => None,
+    }
+}
+
+/// Whether `dir` holds a queue rather than stored values.
+pub(crate) fn is_queue_dir(dir: &Path) -> bool {
+    dir.join(MARKER_FILE).is_file()
+}
+
+fn item_name(seq: u64) -> String {
+    format!("{:020}.{}", seq, ITEM_EXT)
+}
+
+fn lease_name(seq: u64, token: &str) -> String {
+    format!("{:020}.{}.{}", seq, token, LEASE_EXT)
+}
+
+fn new_token() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("{:x}{:x}{:x}", std::process::id(), nanos, LEASE_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+impl Queue {
+    /// Opens the queue named `queue_name` for the given app, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `app_name`: The name of the app.
+    /// * `queue_name`: The name of the queue. It is used as a directory name and, like a
+    ///   namespace, may not be empty, absolute or contain `..`.
+    pub fn open(app_name: impl Into<String>, queue_name: impl Into<String>) -> io::Result<Queue> {
+        let queue_name = queue_name.into();
+        if queue_name.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "queue name is empty"));
+        }
+        let dir = crate::namespace_path(&app_name.into(), &queue_name)?;
+        crate::permissions::create_dirs(&dir)?;
+        crate::permissions::open(&dir.join(MARKER_FILE), OpenOptions::new().write(true).create(true).truncate(false))?;
+        Ok(Queue { dir, lease_timeout: DEFAULT_LEASE_TIMEOUT })
+    }
+
+    /// Sets how long a dequeued item may stay unacknowledged before it is delivered
+    /// again. Defaults to five minutes.
+    pub fn with_lease_timeout(mut self, lease_timeout: Duration) -> Queue {
+        self.lease_timeout = lease_timeout;
+        self
+    }
+
+    fn lock(&self) -> io::Result<File> {
+        let file = crate::permissions::open(&self.dir.join(LOCK_FILE), OpenOptions::new().write(true).create(true))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn next_seq(&self) -> io::Result<u64> {
+        let path = self.dir.join(SEQ_FILE);
+        let seq = match fs::read_to_string(&path) {
+            Ok(contents) => contents.trim().parse::<u64>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        crate::atomic_write(&self.dir.join(SEQ_FILE), (seq + 1).to_string().as_bytes())?;
+        Ok(seq)
+    }
+
+    /// Item file names in the queue directory, sorted oldest first.
+    fn entries(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if parse_name(&name).is_some() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Adds a value to the back of the queue and returns its id.
+    pub fn enqueue<T: ToString>(&self, value: T) -> io::Result<u64> {
+        let _lock = self.lock()?;
+        let seq = self.next_seq()?;
+        crate::atomic_write(&self.dir.join(item_name(seq)), value.to_string().as_bytes())?;
+        Ok(seq)
+    }
+
+    /// Takes the oldest available item out of the queue, or returns `None` if the queue
+    /// is empty. Leases that have outlived the lease timeout are made available first.
+    pub fn dequeue(&self) -> io::Result<Option<Lease>> {
+        let _lock = self.lock()?;
+        let now = SystemTime::now();
+        let mut oldest = None;
+        for name in self.entries()? {
+            let Some((seq, token)) = parse_name(&name) else { continue };
+            if token.is_some() {
+                let leased_at = fs::metadata(self.dir.join(&name))?.modified()?;
+                if leased_at + self.lease_timeout > now {
+                    continue;
+                }
+            }
+            oldest = Some((seq, name));
+            break;
+        }
+
+        let Some((seq, name)) = oldest else { return Ok(None) };
+        let token = new_token();
+        let path = self.dir.join(lease_name(seq, &token));
+        rename(self.dir.join(name), &path)?;
+        crate::permissions::open(&path, OpenOptions::new().write(true))?.set_modified(now)?;
+        let value = fs::read_to_string(&path)?;
+        Ok(Some(Lease { id: seq, value, token }))
+    }
+
+    /// Removes a processed item from the queue for good.
+    ///
+    /// Fails with `NotFound` if the lease expired and the item was handed out again.
+    pub fn ack(&self, lease: Lease) -> io::Result<()> {
+        let _lock = self.lock()?;
+        remove_file(self.dir.join(lease_name(lease.id, &lease.token)))
+    }
+
+    /// Returns an item to the queue so it is delivered again on the next `dequeue`.
+    ///
+    /// Fails with `NotFound` if the lease expired and the item was handed out again.
+    pub fn nack(&self, lease: Lease) -> io::Result<()> {
+        let _lock = self.lock()?;
+        rename(self.dir.join(lease_name(lease.id, &lease.token)), self.dir.join(item_name(lease.id)))
+    }
+
+    /// Returns every outstanding lease to the queue, whether it has expired or not. Call
+    /// this at startup when no other consumer can be holding leases.
+    pub fn recover(&self) -> io::Result<()> {
+        let _lock = self.lock()?;
+        for name in self.entries()? {
+            if let Some((seq, Some(_))) = parse_name(&name) {
+                rename(self.dir.join(&name), self.dir.join(item_name(seq)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of items in the queue, including leased ones.
+    pub fn len(&self) -> io::Result<usize> {
+        let _lock = self.lock()?;
+        Ok(self.entries()?.len())
+    }
+
+    /// Returns `true` if the queue holds no items, leased or not.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod queue_test {
+    use super::*;
+
+    fn test_queue(name: &str) -> Queue {
+        let queue = Queue::open("prefstore_queue_test", name).unwrap();
+        let _ = fs::remove_dir_all(&queue.dir);
+        Queue::open("prefstore_queue_test", name).unwrap()
+    }
+
+    #[test]
+    fn test_fifo_ack_nack() {
+        let queue = test_queue("fifo");
+        queue.enqueue("a").unwrap();
+        queue.enqueue("b").unwrap();
+        queue.enqueue("c").unwrap();
+
+        let a = queue.dequeue().unwrap().unwrap();
+        assert_eq!(a.value, "a");
+        queue.ack(a).unwrap();
+
+        let b = queue.dequeue().unwrap().unwrap();
+        assert_eq!(b.value, "b");
+        queue.nack(b).unwrap();
+
+        assert_eq!(queue.dequeue().unwrap().unwrap().value, "b");
+        assert_eq!(queue.dequeue().unwrap().unwrap().value, "c");
+        assert_eq!(queue.dequeue().unwrap(), None);
+        assert_eq!(queue.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_expired_lease_is_redelivered() {
+        let queue = test_queue("expiry").with_lease_timeout(Duration::ZERO);
+        queue.enqueue("job").unwrap();
+
+        let first = queue.dequeue().unwrap().unwrap();
+        let second = queue.dequeue().unwrap().unwrap();
+        assert_eq!(first.id, second.id);
+        assert!(queue.ack(first).is_err());
+        queue.ack(second).unwrap();
+        assert!(queue.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_recover_returns_leases() {
+        let queue = test_queue("recover");
+        queue.enqueue("job").unwrap();
+        let _lease = queue.dequeue().unwrap().unwrap();
+        assert_eq!(queue.dequeue().unwrap(), None);
+
+        // A new consumer starting after a crash.
+        let restarted = Queue::open("prefstore_queue_test", "recover").unwrap();
+        restarted.recover().unwrap();
+        assert_eq!(restarted.dequeue().unwrap().unwrap().value, "job");
+    }
+
+    #[test]
+    fn test_concurrent_consumers_get_distinct_items() {
+        let queue = test_queue("concurrent");
+        for i in 0..50 {
+            queue.enqueue(i).unwrap();
+        }
+        let handles: Vec<_> = (0..4).map(|_| {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                let mut seen = Vec::new();
+                while let Some(lease) = queue.dequeue().unwrap() {
+                    seen.push(lease.id);
+                    queue.ack(lease).unwrap();
+                }
+                seen
+            })
+        }).collect();
+        let mut seen: Vec<u64> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        seen.sort();
+        assert_eq!(seen, (0..50).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_queue_names_are_validated() {
+        for name in ["", "../escape", "/tmp/queue"] {
+            assert_eq!(Queue::open("prefstore_queue_test", name).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Declared preferences: their types, defaults and allowed values.
+//!
+//! A [`Schema`] is registered once per app with [`register_schema`]. From then on
+//! `savepreference`, `savecustom`, `initcustomfile` and the `appendcustom` functions
+//! reject values that do not fit a declared key, `savebuffer` refuses declared keys
+//! altogether, `getpreference` uses
+//! the declared default instead of the one passed at the call site, and
+//! [`getschemapreference`] and [`getschemavalue`] read keys without any default argument.
+//! Keys the schema does not declare behave as before.
+
+use std::{collections::{BTreeMap, HashMap}, io, sync::{Arc, Mutex}};
+
+static SCHEMAS: Mutex<Option<HashMap<String, Arc<Schema>>>> = Mutex::new(None);
+
+/// The type of a declared preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefType {
+    /// `true`, `t`, `false` or `f`, as understood by `ems::tobool`.
+    Bool,
+    /// A whole number that fits an `i128`.
+    Int,
+    /// A finite floating point number.
+    Float,
+    /// Any text.
+    Text,
+}
+
+/// A preference value converted according to its declared type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrefValue {
+    /// The value of a `Bool` preference.
+    Bool(bool),
+    /// The value of an `Int` preference.
+    Int(i128),
+    /// The value of a `Float` preference.
+    Float(f64),
+    /// The value of a `Text` preference.
+    Text(String),
+}
+
+impl PrefValue {
+    /// The value if it is a `Bool`.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            PrefValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is an `Int`.
+    pub fn as_int(&self) -> Option<i128> {
+        match self {
+            PrefValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is a `Float`, or an `Int` converted to a float.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            PrefValue::Float(value) => Some(*value),
+            PrefValue::Int(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    /// The value if it is `Text`.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            PrefValue::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// The declaration of a single preference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefSpec {
+    key: String,
+    kind: PrefType,
+    default: String,
+    min: Option<f64>,
+    max: Option<f64>,
+    allowed: Vec<String>,
+    description: String,
+}
+
+impl PrefSpec {
+    /// Declares the preference `key` of the given type with a default value.
+    pub fn new<T: ToString>(key: impl Into<String>, kind: PrefType, default: T) -> PrefSpec {
+        PrefSpec {
+            key: key.into(),
+            kind,
+            default: default.to_string(),
+            min: None,
+            max: None,
+            allowed: Vec::new(),
+            description: String::new(),
+        }
+    }
+
+    /// Restricts a numeric preference to `min..=max`.
+    pub fn range(mut self, min: f64, max: f64) -> PrefSpec {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+
+    /// Restricts the preference to one of the given values.
+    pub fn one_of<T: ToString>(mut self, allowed: &[T]) -> PrefSpec {
+        self.allowed = allowed.iter().map(|value| value.to_string()).collect();
+        self
+    }
+
+    /// Describes the preference, for settings screens and error messages.
+    pub fn description(mut self, description: impl Into<String>) -> PrefSpec {
+        self.description = description.into();
+        self
+    }
+
+    /// The declared key.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The declared type.
+    pub fn kind(&self) -> PrefType {
+        self.kind
+    }
+
+    /// The default value, as stored.
+    pub fn default_value(&self) -> &str {
+        &self.default
+    }
+
+    /// The description, or `""` if none was given.
+    pub fn describe(&self) -> &str {
+        &self.description
+    }
+
+    /// What values are accepted, for error messages.
+    fn expectation(&self) -> String {
+        let mut expected = match self.kind {
+            PrefType::Bool => "a boolean (true, t, false or f)".to_string(),
+            PrefType::Int => "an integer".to_string(),
+            PrefType::Float => "a number".to_string(),
+            PrefType::Text => "text".to_string(),
+        };
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            expected.push_str(&format!(" between {} and {}", min, max));
+        }
+        if !self.allowed.is_empty() {
+            expected.push_str(&format!(" out of {:?}", self.allowed));
+        }
+        expected
+    }
+
+    /// Converts `value` according to the declaration, or explains why it does not fit.
+    pub fn parse(&self, value: &str) -> io::Result<PrefValue> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid value {:?} for preference {:?}: expected {}", value, self.key, self.expectation()));
+
+        let parsed = match self.kind {
+            PrefType::Bool => match value {
+                "true" | "t" => PrefValue::Bool(true),
+                "false" | "f" => PrefValue::Bool(false),
+                _ => return Err(invalid()),
+            },
+            PrefType::Int => PrefValue::Int(value.parse::<i128>().map_err(|_| invalid())?),
+            PrefType::Float => {
+                let number = value.parse::<f64>().map_err(|_| invalid())?;
+                if !number.is_finite() {
+                    return Err(invalid());
+                }
+                PrefValue::Float(number)
+            },
+            PrefType::Text => PrefValue::Text(value.to_string()),
+        };
+        if let Some(number) = parsed.as_float() {
+            if self.min.map(|min| number < min).unwrap_or(false) || self.max.map(|max| number > max).unwrap_or(false) {
+                return Err(invalid());
+            }
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|allowed| allowed == value) {
+            return Err(invalid());
+        }
+        Ok(parsed)
+    }
+}
+
+/// The declared preferences of an app.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{getschemapreference, register_schema, savepreference, PrefSpec, PrefType, Schema};
+///
+/// let schema = Schema::new()
+///     .pref(PrefSpec::new("theme", PrefType::Text, "light").one_of(&["light", "dark"]).description("Colour theme"))
+///     .pref(PrefSpec::new("font_size", PrefType::Int, 12).range(6.0, 72.0));
+/// register_schema("myapp", schema).unwrap();
+///
+/// assert!(savepreference("myapp", "font_size", 200).is_err());
+/// savepreference("myapp", "theme", "dark").unwrap();
+/// assert_eq!(getschemapreference("myapp", "theme").unwrap(), "dark");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schema {
+    prefs: BTreeMap<String, PrefSpec>,
+}
+
+impl Schema {
+    /// An empty schema, to which declarations are added with [`Schema::pref`].
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    /// Adds a declaration, replacing any earlier one for the same key.
+    pub fn pref(mut self, spec: PrefSpec) -> Schema {
+        self.prefs.insert(spec.key.clone(), spec);
+        self
+    }
+
+    /// The declaration of `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&PrefSpec> {
+        self.prefs.get(key)
+    }
+
+    /// All declarations, ordered by key.
+    pub fn prefs(&self) -> impl Iterator<Item = &PrefSpec> {
+        self.prefs.values()
+    }
+}
+
+/// Makes `schema` the schema of `app_name`, replacing any earlier one.
+///
+/// # Errors
+///
+/// Fails with `InvalidInput` if a declared default does not fit its own declaration.
+pub fn register_schema(app_name: impl Into<String>, schema: Schema) -> io::Result<()> {
+    for spec in schema.prefs() {
+        spec.parse(&spec.default)?;
+    }
+    SCHEMAS.lock().unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(app_name.into(), Arc::new(schema));
+    Ok(())
+}
+
+/// Returns the schema registered for `app_name`, if any.
+pub fn schema_for(app_name: &str) -> Option<Arc<Schema>> {
+    SCHEMAS.lock().unwrap_or_else(|e| e.into_inner()).as_ref()?.get(app_name).cloned()
+}
+
+/// The declaration of the preference stored in `file_name`, such as `theme.txt`.
+pub(crate) fn spec_for_file(app_name: &str, file_name: &str) -> Option<PrefSpec> {
+    let key = file_name.strip_suffix(".txt")?;
+    schema_for(app_name)?.get(key).cloned()
+}
+
+fn declared(app_name: &str, key: &str) -> io::Result<PrefSpec> {
+    schema_for(app_name)
+        .and_then(|schema| schema.get(key).cloned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("preference {:?} is not declared in the schema of {:?}", key, app_name)))
+}
+
+/// The stored value of a declared preference, or its default if nothing valid is stored.
+fn stored_or_default(app_name: &str, spec: &PrefSpec) -> io::Result<String> {
+    let stored = match crate::alias::fallback(app_name, &spec.key)? {
+        Some(value) => value,
+        None => crate::getcustom(app_name, format!("{}.txt", spec.key), &spec.default)?,
+    };
+    Ok(if spec.parse(&stored).is_ok() { stored } else { spec.default.clone() })
+}
+
+/// Retrieves a declared preference, falling back to the default from the app's schema if
+/// it is missing or holds a value the schema does not accept.
+///
+/// # Errors
+///
+/// Fails with `NotFound` if the app has no schema or the schema does not declare `key`.
+pub fn getschemapreference(app_name: impl Into<String>, key: impl Into<String>) -> io::Result<String> {
+    let app_name = app_name.into();
+    let spec = declared(&app_name, &crate::alias::resolve(&app_name, &key.into()))?;
+    stored_or_default(&app_name, &spec)
+}
+
+/// Like [`getschemapreference`], converted according to the declared type.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{getschemavalue, register_schema, PrefSpec, PrefType, Schema};
+///
+/// register_schema("myapp", Schema::new().pref(PrefSpec::new("autosave", PrefType::Bool, true))).unwrap();
+/// let autosave = getschemavalue("myapp", "autosave").unwrap().as_bool();
+/// ```
+pub fn getschemavalue(app_name: impl Into<String>, key: impl Into<String>) -> io::Result<PrefValue> {
+    let app_name = app_name.into();
+    let spec = declared(&app_name, &crate::alias::resolve(&app_name, &key.into()))?;
+    spec.parse(&stored_or_default(&app_name, &spec)?)
+}
+
+/// The value `getpreference` returns for a declared preference.
+pub(crate) fn declared_preference(app_name: &str, key: &str) -> Option<String> {
+    let spec = schema_for(app_name)?.get(key).cloned()?;
+    Some(stored_or_default(app_name, &spec).unwrap_or(spec.default))
+}
+
+#[cfg(test)]
+mod schema_test {
+    use super::*;
+    use crate::{clearpreference, getpreference, savecustom, savepreference};
+
+    #[test]
+    fn test_schema_validates_and_defaults() {
+        let app_name = "prefstore_schema_test";
+        let schema = Schema::new()
+            .pref(PrefSpec::new("theme", PrefType::Text, "light").one_of(&["light", "dark"]))
+            .pref(PrefSpec::new("font_size", PrefType::Int, 12).range(6.0, 72.0))
+            .pref(PrefSpec::new("autosave", PrefType::Bool, "t"));
+        register_schema(app_name, schema).unwrap();
+        let _ = clearpreference(app_name, "theme");
+        let _ = clearpreference(app_name, "font_size");
+
+        let error = savepreference(app_name, "font_size", 200).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("between 6 and 72"));
+        assert!(savepreference(app_name, "theme", "blue").is_err());
+        assert!(savepreference(app_name, "autosave", "yes").is_err());
+        savepreference(app_name, "undeclared", "anything").unwrap();
+
+        // The call site default is ignored in favour of the declared one.
+        assert_eq!(getpreference(app_name, "font_size", 99), "12");
+        savepreference(app_name, "font_size", 14).unwrap();
+        assert_eq!(getschemavalue(app_name, "font_size").unwrap(), PrefValue::Int(14));
+
+        // Appending must leave a valid value, and a declared key cannot hold a buffer.
+        assert!(crate::appendcustom(app_name, "font_size.txt", "0").is_err());
+        savepreference(app_name, "font_size", 6).unwrap();
+        crate::appendcustom(app_name, "font_size.txt", "0").unwrap();
+        assert_eq!(getschemavalue(app_name, "font_size").unwrap(), PrefValue::Int(60));
+        assert!(crate::appendcustomnewline(app_name, "font_size.txt", "1").is_err());
+        assert_eq!(crate::savebuffer(app_name, "font_size.txt", "12", 3).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        savepreference(app_name, "font_size", 14).unwrap();
+        assert_eq!(getschemavalue(app_name, "autosave").unwrap(), PrefValue::Bool(true));
+
+        // A value edited by hand that no longer fits falls back to the default.
+        std::fs::write(crate::config_folder_path(app_name).unwrap().join("theme.txt"), "blue").unwrap();
+        assert_eq!(getschemapreference(app_name, "theme").unwrap(), "light");
+        assert_eq!(getpreference(app_name, "theme", "dark"), "light");
+
+        assert_eq!(getschemapreference(app_name, "undeclared").unwrap_err().kind(), io::ErrorKind::NotFound);
+        savecustom(app_name, "notes.md", "not a preference").unwrap();
+    }
+
+    #[test]
+    fn test_schema_reads_resolve_aliases() {
+        let app_name = "prefstore_schema_alias_test";
+        register_schema(app_name, Schema::new().pref(PrefSpec::new("font_size", PrefType::Int, 12))).unwrap();
+        crate::register_alias(app_name, crate::Alias::new("fontsize", "font_size")).unwrap();
+        savepreference(app_name, "font_size", 14).unwrap();
+        assert_eq!(getschemapreference(app_name, "fontsize").unwrap(), "14");
+        assert_eq!(getschemavalue(app_name, "fontsize").unwrap(), PrefValue::Int(14));
+    }
+
+    #[test]
+    fn test_invalid_default_is_rejected() {
+        let schema = Schema::new().pref(PrefSpec::new("level", PrefType::Int, 0).range(1.0, 5.0));
+        assert!(register_schema("prefstore_schema_invalid_test", schema).is_err());
+        assert!(schema_for("prefstore_schema_invalid_test").is_none());
+    }
+}
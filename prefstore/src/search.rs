@@ -0,0 +1,146 @@
+//! Searching stored values for text.
+
+use std::{io, ops::Range};
+
+use crate::Store;
+
+/// What [`search`] looks for in each line of a stored value.
+#[derive(Debug, Clone)]
+pub enum SearchPattern {
+    /// A literal, case-sensitive substring.
+    Substring(String),
+    /// A regular expression. Requires the `regex` feature.
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl From<&str> for SearchPattern {
+    fn from(text: &str) -> SearchPattern {
+        SearchPattern::Substring(text.to_string())
+    }
+}
+
+impl From<String> for SearchPattern {
+    fn from(text: String) -> SearchPattern {
+        SearchPattern::Substring(text)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl From<regex::Regex> for SearchPattern {
+    fn from(regex: regex::Regex) -> SearchPattern {
+        SearchPattern::Regex(regex)
+    }
+}
+
+impl SearchPattern {
+    /// Byte ranges of the non-overlapping matches in `line`.
+    fn find_in(&self, line: &str) -> Vec<Range<usize>> {
+        match self {
+            SearchPattern::Substring(needle) if needle.is_empty() => Vec::new(),
+            SearchPattern::Substring(needle) => line.match_indices(needle.as_str())
+                .map(|(start, found)| start..start + found.len())
+                .collect(),
+            #[cfg(feature = "regex")]
+            SearchPattern::Regex(regex) => regex.find_iter(line)
+                .filter(|found| !found.is_empty())
+                .map(|found| found.range())
+                .collect(),
+        }
+    }
+}
+
+/// A match found by [`search`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    /// The key whose value matched.
+    pub key: String,
+    /// The line of the value that matched, starting at 1.
+    pub line: usize,
+    /// The byte range of the match within the line.
+    pub span: Range<usize>,
+    /// The full text of the matching line.
+    pub text: String,
+}
+
+/// Finds every stored value of the given app containing `pattern`.
+///
+/// Values are searched line by line, so each match reports its line number and the
+/// byte range it covers within that line.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `pattern` - A substring, or with the `regex` feature a `regex::Regex`.
+///
+/// # Returns
+///
+/// The matches, ordered by key, line and position.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::search;
+///
+/// for found in search("myapp", "proxy.example.com").unwrap() {
+///     println!("{}:{}: {}", found.key, found.line, found.text);
+/// }
+/// ```
+pub fn search(app_name: impl Into<String>, pattern: impl Into<SearchPattern>) -> io::Result<Vec<SearchMatch>> {
+    Store::new(app_name)?.search("", pattern)
+}
+
+impl Store {
+    /// Finds every value in a namespace containing `pattern`. See [`search`].
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first value that cannot be read.
+    pub fn search(&self, namespace: &str, pattern: impl Into<SearchPattern>) -> io::Result<Vec<SearchMatch>> {
+        let pattern = pattern.into();
+        let mut matches = Vec::new();
+        for entry in self.iter(namespace)? {
+            let (key, value) = entry?;
+            for (index, line) in value.lines().enumerate() {
+                for span in pattern.find_in(line) {
+                    matches.push(SearchMatch { key: key.clone(), line: index + 1, span, text: line.to_string() });
+                }
+            }
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod search_test {
+    use super::*;
+
+    fn test_store(app_name: &str) -> Store {
+        let store = Store::new(app_name).unwrap();
+        let _ = std::fs::remove_dir_all(store.dir());
+        crate::savepreference(app_name, "proxy", "http://proxy.local:8080").unwrap();
+        crate::savecustom(app_name, "net/config.toml", "[net]\nproxy = \"http://proxy.local:3128\"\n").unwrap();
+        crate::savepreference(app_name, "theme", "dark").unwrap();
+        store
+    }
+
+    #[test]
+    fn test_substring_search() {
+        test_store("prefstore_search_test");
+        let matches = search("prefstore_search_test", "proxy.local").unwrap();
+        assert_eq!(matches, vec![
+            SearchMatch { key: "net/config.toml".to_string(), line: 2, span: 16..27, text: "proxy = \"http://proxy.local:3128\"".to_string() },
+            SearchMatch { key: "proxy.txt".to_string(), line: 1, span: 7..18, text: "http://proxy.local:8080".to_string() },
+        ]);
+        assert!(search("prefstore_search_test", "").unwrap().is_empty());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_search() {
+        let store = test_store("prefstore_search_regex_test");
+        let matches = store.search("", regex::Regex::new(r":\d+").unwrap()).unwrap();
+        let spans: Vec<(&str, usize, Range<usize>)> = matches.iter().map(|m| (m.key.as_str(), m.line, m.span.clone())).collect();
+        assert_eq!(spans, vec![("net/config.toml", 2, 27..32), ("proxy.txt", 1, 18..23)]);
+    }
+}
@@ -0,0 +1,524 @@
+//! Preferences encrypted at rest, available with the `encryption` feature.
+//!
+//! Secrets are encrypted with XChaCha20-Poly1305 and stored as `{key}.secret` files holding
+//! a single line: `prefstore-secret v1 {key id} {nonce} {ciphertext}`, the last two in
+//! hex. The app name and key are authenticated along with the value, so a secret copied
+//! to another key or app fails to decrypt just like an edited one. The key id records
+//! which key of a [`KeyProvider`] encrypted the value, so keys can be rotated: old values
+//! stay readable while new ones use the current key, and [`reencrypt_secrets`] moves every
+//! value over to it.
+
+use std::{fmt, fs, io, path::{Path, PathBuf}, sync::OnceLock};
+
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+const MAGIC: &str = "prefstore-secret";
+const FORMAT_VERSION: &str = "v1";
+const SECRET_EXTENSION: &str = "secret";
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit encryption key. Its bytes are never printed and are zeroed when dropped.
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Wraps existing key bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> SecretKey {
+        SecretKey(bytes)
+    }
+
+    /// Generates a random key from the operating system's random number generator.
+    pub fn generate() -> SecretKey {
+        SecretKey(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// The key's bytes, for example to store them in a key file.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+/// Supplies the keys secrets are encrypted with, each identified by a number.
+///
+/// New values are encrypted with the key `current_key_id` returns. Reading a value asks
+/// for the key it was encrypted with, so a provider that still knows retired keys can
+/// read values written before a rotation. [`Passphrase`], [`KeyFile`] and [`KeyFn`] each
+/// hold one key; combine them with a [`KeyRing`] to rotate.
+pub trait KeyProvider {
+    /// The id of the key new values are encrypted with.
+    fn current_key_id(&self) -> u32;
+
+    /// The key with the given id.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `NotFound` if the provider has no key with that id.
+    fn key(&self, key_id: u32) -> io::Result<SecretKey>;
+}
+
+fn unknown_key(key_id: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no encryption key with id {}", key_id))
+}
+
+/// A key derived from a passphrase with Argon2.
+///
+/// The salt should be unique to the application and at least 8 bytes long. The key is
+/// derived once, the first time it is needed.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{get_secret, save_secret, Passphrase};
+///
+/// let keys = Passphrase::new("correct horse battery staple", "myapp salt");
+/// save_secret("myapp", "api_token", "s3cr3t", &keys).unwrap();
+/// assert_eq!(get_secret("myapp", "api_token", &keys).unwrap(), "s3cr3t");
+/// ```
+pub struct Passphrase {
+    id: u32,
+    passphrase: String,
+    salt: Vec<u8>,
+    derived: OnceLock<SecretKey>,
+}
+
+impl Passphrase {
+    /// Uses the key derived from `passphrase` and `salt`, with id 1.
+    pub fn new(passphrase: impl Into<String>, salt: impl Into<Vec<u8>>) -> Passphrase {
+        Passphrase { id: 1, passphrase: passphrase.into(), salt: salt.into(), derived: OnceLock::new() }
+    }
+
+    /// Sets the id of the key, for use in a [`KeyRing`].
+    pub fn id(mut self, id: u32) -> Passphrase {
+        self.id = id;
+        self
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Passphrase").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.passphrase);
+    }
+}
+
+impl KeyProvider for Passphrase {
+    fn current_key_id(&self) -> u32 {
+        self.id
+    }
+
+    fn key(&self, key_id: u32) -> io::Result<SecretKey> {
+        if key_id != self.id {
+            return Err(unknown_key(key_id));
+        }
+        if let Some(key) = self.derived.get() {
+            return Ok(key.clone());
+        }
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        argon2::Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), &self.salt, bytes.as_mut())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("could not derive a key from the passphrase: {}", e)))?;
+        Ok(self.derived.get_or_init(|| SecretKey(*bytes)).clone())
+    }
+}
+
+/// A key read from a file holding exactly 32 bytes.
+///
+/// # Examples
+///
+/// ```no_run
+/// use prefstore::KeyFile;
+///
+/// let keys = KeyFile::create("/etc/myapp/prefs.key").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyFile {
+    id: u32,
+    path: PathBuf,
+}
+
+impl KeyFile {
+    /// Uses the key stored at `path`, with id 1. The file is read each time the key is
+    /// needed.
+    pub fn new(path: impl Into<PathBuf>) -> KeyFile {
+        KeyFile { id: 1, path: path.into() }
+    }
+
+    /// Like [`KeyFile::new`], first writing a newly generated key to `path` if it does
+    /// not exist yet. On Unix the file is only readable by its owner.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<KeyFile> {
+        let path = path.into();
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                crate::permissions::create_dirs(parent)?;
+            }
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(mut file) => io::Write::write_all(&mut file, SecretKey::generate().as_bytes())?,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(KeyFile::new(path))
+    }
+
+    /// Sets the id of the key, for use in a [`KeyRing`].
+    pub fn id(mut self, id: u32) -> KeyFile {
+        self.id = id;
+        self
+    }
+}
+
+impl KeyProvider for KeyFile {
+    fn current_key_id(&self) -> u32 {
+        self.id
+    }
+
+    fn key(&self, key_id: u32) -> io::Result<SecretKey> {
+        if key_id != self.id {
+            return Err(unknown_key(key_id));
+        }
+        let bytes = Zeroizing::new(fs::read(&self.path)?);
+        let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("key file {:?} holds {} bytes instead of 32", self.path, bytes.len()))
+        })?;
+        Ok(SecretKey(bytes))
+    }
+}
+
+/// A key supplied by a function, for example one reading the system keychain.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{KeyFn, SecretKey};
+///
+/// let keys = KeyFn::new(1, |_id| Ok(SecretKey::from_bytes([7; 32])));
+/// ```
+pub struct KeyFn<F> {
+    current: u32,
+    key: F,
+}
+
+impl<F: Fn(u32) -> io::Result<SecretKey>> KeyFn<F> {
+    /// Calls `key` with the id of every key needed, encrypting new values with the key
+    /// `current`.
+    pub fn new(current: u32, key: F) -> KeyFn<F> {
+        KeyFn { current, key }
+    }
+}
+
+impl<F> fmt::Debug for KeyFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyFn").field("current", &self.current).finish_non_exhaustive()
+    }
+}
+
+impl<F: Fn(u32) -> io::Result<SecretKey>> KeyProvider for KeyFn<F> {
+    fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn key(&self, key_id: u32) -> io::Result<SecretKey> {
+        (self.key)(key_id)
+    }
+}
+
+/// Several providers, one of which encrypts new values while the others only decrypt
+/// values written before a key rotation.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{KeyRing, Passphrase};
+///
+/// let keys = KeyRing::new(Passphrase::new("new passphrase", "myapp salt").id(2))
+///     .retired(Passphrase::new("old passphrase", "myapp salt").id(1));
+/// ```
+pub struct KeyRing {
+    current: Box<dyn KeyProvider + Send + Sync>,
+    retired: Vec<Box<dyn KeyProvider + Send + Sync>>,
+}
+
+impl KeyRing {
+    /// Encrypts new values with the current key of `current`.
+    pub fn new(current: impl KeyProvider + Send + Sync + 'static) -> KeyRing {
+        KeyRing { current: Box::new(current), retired: Vec::new() }
+    }
+
+    /// Adds a provider whose keys are only used to read older values.
+    pub fn retired(mut self, provider: impl KeyProvider + Send + Sync + 'static) -> KeyRing {
+        self.retired.push(Box::new(provider));
+        self
+    }
+}
+
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRing").field("current", &self.current.current_key_id()).finish_non_exhaustive()
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key_id(&self) -> u32 {
+        self.current.current_key_id()
+    }
+
+    fn key(&self, key_id: u32) -> io::Result<SecretKey> {
+        for provider in std::iter::once(&self.current).chain(&self.retired) {
+            match provider.key(key_id) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => return result,
+            }
+        }
+        Err(unknown_key(key_id))
+    }
+}
+
+fn secret_path(app_name: &str, key: &str) -> io::Result<PathBuf> {
+    crate::namespace_path(app_name, &format!("{}.{}", key, SECRET_EXTENSION))
+}
+
+/// The data authenticated along with a value, binding it to its app and key.
+fn associated_data(app_name: &str, key: &str) -> Vec<u8> {
+    format!("{}\0{}", app_name, key).into_bytes()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn encrypt(app_name: &str, key: &str, value: &[u8], keys: &impl KeyProvider) -> io::Result<String> {
+    let key_id = keys.current_key_id();
+    let cipher = XChaCha20Poly1305::new(keys.key(key_id)?.as_bytes().into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(app_name, key);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: value, aad: &aad })
+        .map_err(|_| io::Error::other(format!("could not encrypt secret {:?}", key)))?;
+    Ok(format!("{} {} {} {} {}\n", MAGIC, FORMAT_VERSION, key_id, to_hex(&nonce), to_hex(&ciphertext)))
+}
+
+/// Splits a stored secret into its key id, nonce and ciphertext.
+fn parse(key: &str, stored: &str) -> io::Result<(u32, Vec<u8>, Vec<u8>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, format!("secret {:?} is not in a format prefstore can read", key));
+    let mut fields = stored.trim_end().split(' ');
+    if fields.next() != Some(MAGIC) {
+        return Err(malformed());
+    }
+    match fields.next() {
+        Some(FORMAT_VERSION) => {},
+        Some(version) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("secret {:?} uses unsupported format {}", key, version)));
+        },
+        None => return Err(malformed()),
+    }
+    let key_id = fields.next().and_then(|id| id.parse().ok()).ok_or_else(malformed)?;
+    let nonce = fields.next().and_then(from_hex).filter(|nonce| nonce.len() == NONCE_LEN).ok_or_else(malformed)?;
+    let ciphertext = fields.next().and_then(from_hex).ok_or_else(malformed)?;
+    if fields.next().is_some() {
+        return Err(malformed());
+    }
+    Ok((key_id, nonce, ciphertext))
+}
+
+fn decrypt(app_name: &str, key: &str, stored: &str, keys: &impl KeyProvider) -> io::Result<(u32, Vec<u8>)> {
+    let (key_id, nonce, ciphertext) = parse(key, stored)?;
+    let cipher = XChaCha20Poly1305::new(keys.key(key_id)?.as_bytes().into());
+    let aad = associated_data(app_name, key);
+    let value = cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad }).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("could not decrypt secret {:?}: the key is wrong or the file was modified", key),
+        )
+    })?;
+    Ok((key_id, value))
+}
+
+fn write_secret(app_name: &str, path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        crate::permissions::create_dirs(parent)?;
+    }
+    crate::tracked_write(app_name, path, || crate::atomic_write(path, contents.as_bytes()))
+}
+
+/// Encrypts `value` and stores it as the secret `key` of the app, replacing any previous
+/// value. Secrets are kept apart from plain preferences: `getpreference` does not see them.
+///
+/// # Arguments
+///
+/// * `app_name` - The name of the application.
+/// * `key` - The name of the secret, which may include a namespace such as `"sync/token"`.
+/// * `value` - The value to encrypt.
+/// * `keys` - The provider of the encryption key.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{save_secret, KeyFn, SecretKey};
+///
+/// let keys = KeyFn::new(1, |_id| Ok(SecretKey::from_bytes([7; 32])));
+/// save_secret("myapp", "api_token", "s3cr3t", &keys).unwrap();
+/// ```
+///
+/// # Errors
+///
+/// Fails if the key cannot be obtained from the provider or the file cannot be written.
+pub fn save_secret(app_name: impl Into<String>, key: impl Into<String>, value: impl AsRef<[u8]>, keys: &impl KeyProvider) -> io::Result<()> {
+    let (app_name, key) = (app_name.into(), key.into());
+    let path = secret_path(&app_name, &key)?;
+    let contents = encrypt(&app_name, &key, value.as_ref(), keys)?;
+    write_secret(&app_name, &path, &contents)
+}
+
+/// Decrypts the secret `key` of the app as raw bytes.
+///
+/// # Errors
+///
+/// Fails with `NotFound` if the secret is not stored or the provider lacks the key it was
+/// encrypted with, and with `InvalidData` if the key is wrong or the file was modified.
+pub fn get_secret_bytes(app_name: impl Into<String>, key: impl Into<String>, keys: &impl KeyProvider) -> io::Result<Vec<u8>> {
+    let (app_name, key) = (app_name.into(), key.into());
+    let stored = fs::read_to_string(secret_path(&app_name, &key)?)?;
+    decrypt(&app_name, &key, &stored, keys).map(|(_, value)| value)
+}
+
+/// Decrypts the secret `key` of the app. See [`get_secret_bytes`] for the errors.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{get_secret, save_secret, KeyFn, SecretKey};
+///
+/// let keys = KeyFn::new(1, |_id| Ok(SecretKey::from_bytes([7; 32])));
+/// save_secret("myapp", "api_token", "s3cr3t", &keys).unwrap();
+/// assert_eq!(get_secret("myapp", "api_token", &keys).unwrap(), "s3cr3t");
+/// ```
+pub fn get_secret(app_name: impl Into<String>, key: impl Into<String>, keys: &impl KeyProvider) -> io::Result<String> {
+    let value = get_secret_bytes(app_name, key, keys)?;
+    String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Removes the secret `key` of the app.
+pub fn clear_secret(app_name: impl Into<String>, key: impl Into<String>) -> io::Result<()> {
+    let app_name = app_name.into();
+    let path = secret_path(&app_name, &key.into())?;
+    crate::tracked_write(&app_name, &path, || fs::remove_file(&path))
+}
+
+/// Re-encrypts every secret of the app that was not encrypted with the provider's current
+/// key, after a key rotation. Returns how many secrets were re-encrypted.
+///
+/// # Errors
+///
+/// Stops at the first secret that cannot be decrypted.
+pub fn reencrypt_secrets(app_name: impl Into<String>, keys: &impl KeyProvider) -> io::Result<usize> {
+    let app_name = app_name.into();
+    let app_dir = crate::config_folder_path(&app_name)?;
+    let mut count = 0;
+    for path in crate::namespace_files(&app_name, "", &[SECRET_EXTENSION], crate::Depth::Recursive)? {
+        let relative = crate::relative_key(&app_dir, &path)?;
+        let key = match relative.strip_suffix(&format!(".{}", SECRET_EXTENSION)) {
+            Some(key) => key,
+            None => continue,
+        };
+        let stored = fs::read_to_string(&path)?;
+        let (key_id, value) = decrypt(&app_name, key, &stored, keys)?;
+        if key_id != keys.current_key_id() {
+            write_secret(&app_name, &path, &encrypt(&app_name, key, &value, keys)?)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod secret_test {
+    use super::*;
+
+    fn fixed(id: u32, byte: u8) -> KeyFn<impl Fn(u32) -> io::Result<SecretKey>> {
+        KeyFn::new(id, move |key_id| if key_id == id { Ok(SecretKey::from_bytes([byte; 32])) } else { Err(unknown_key(key_id)) })
+    }
+
+    #[test]
+    fn test_round_trip_and_wrong_key() {
+        let app_name = "prefstore_secret_test";
+        let keys = fixed(1, 7);
+        save_secret(app_name, "sync/token", "s3cr3t", &keys).unwrap();
+        assert_eq!(get_secret(app_name, "sync/token", &keys).unwrap(), "s3cr3t");
+
+        let path = crate::config_folder_path(app_name).unwrap().join("sync/token.secret");
+        let stored = fs::read_to_string(&path).unwrap();
+        assert!(stored.starts_with("prefstore-secret v1 1 "));
+        assert!(!stored.contains("s3cr3t"));
+
+        let error = get_secret(app_name, "sync/token", &KeyFn::new(1, |_| Ok(SecretKey::from_bytes([8; 32])))).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("key is wrong or the file was modified"));
+        assert_eq!(get_secret(app_name, "sync/token", &fixed(2, 7)).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // A value moved to another key does not decrypt.
+        fs::copy(&path, path.with_file_name("other.secret")).unwrap();
+        assert_eq!(get_secret(app_name, "sync/other", &keys).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Nor does an edited one.
+        let mut tampered = stored.trim_end().to_string();
+        let last = if tampered.ends_with('0') { '1' } else { '0' };
+        tampered.pop();
+        tampered.push(last);
+        fs::write(&path, tampered).unwrap();
+        assert_eq!(get_secret(app_name, "sync/token", &keys).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::write(&path, "prefstore-secret v9 1 00 00").unwrap();
+        assert!(get_secret(app_name, "sync/token", &keys).unwrap_err().to_string().contains("unsupported format v9"));
+        clear_secret(app_name, "sync/token").unwrap();
+        assert_eq!(get_secret(app_name, "sync/token", &keys).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let app_name = "prefstore_secret_rotation_test";
+        let _ = fs::remove_dir_all(crate::config_folder_path(app_name).unwrap());
+        let old = Passphrase::new("old passphrase", "prefstore test salt");
+        save_secret(app_name, "token", "abc", &old).unwrap();
+        save_secret(app_name, "password", "hunter2", &old).unwrap();
+
+        let keys = KeyRing::new(Passphrase::new("new passphrase", "prefstore test salt").id(2))
+            .retired(Passphrase::new("old passphrase", "prefstore test salt"));
+        assert_eq!(get_secret(app_name, "token", &keys).unwrap(), "abc");
+        save_secret(app_name, "token", "def", &keys).unwrap();
+        assert_eq!(reencrypt_secrets(app_name, &keys).unwrap(), 1);
+        assert_eq!(reencrypt_secrets(app_name, &keys).unwrap(), 0);
+
+        let new = Passphrase::new("new passphrase", "prefstore test salt").id(2);
+        assert_eq!(get_secret(app_name, "token", &new).unwrap(), "def");
+        assert_eq!(get_secret(app_name, "password", &new).unwrap(), "hunter2");
+        assert_eq!(get_secret(app_name, "password", &old).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,128 @@
+//! Keys whose values are masked wherever prefstore lists many values at once.
+//!
+//! Functions reading a whole app or namespace, such as `getall`, `getallcustom`,
+//! `readallcustom` and [`Store::iter`](crate::Store::iter), return [`REDACTED`] in place
+//! of the value of every key marked with [`mark_sensitive`]. Reading a single key, with
+//! `getcustom` or `getpreference`, is an explicit request for that value and is never
+//! masked; [`Entries::reveal`](crate::Entries::reveal) and the `_with` variants taking
+//! [`ReadOptions`] do the same for a listing.
+//!
+//! Values passed to observers and to watches of a namespace are masked too, so they can
+//! be logged as they are. Per-key history is not kept for sensitive keys.
+
+use std::{collections::HashMap, io, sync::Mutex};
+
+static SENSITIVE: Mutex<Option<HashMap<String, Vec<glob::Pattern>>>> = Mutex::new(None);
+
+/// The text shown in place of a sensitive value.
+pub const REDACTED: &str = "<redacted>";
+
+/// Options for `readallcustom_with`, `readallcustombytes_with` and `getall_with`.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// Returns the values of sensitive keys instead of [`REDACTED`].
+    pub reveal_sensitive: bool,
+}
+
+/// Marks the keys of an app matching a glob pattern as sensitive.
+///
+/// The pattern is matched against keys relative to the app's folder, both with and
+/// without their extension, so `"api_token"` marks the preference `api_token` and
+/// `"secrets/**"` every key in the `secrets` namespace. `*` does not match `/`.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{getall, mark_sensitive, savepreference, REDACTED};
+///
+/// savepreference("myapp_sensitive_doc", "api_token", "s3cr3t").unwrap();
+/// mark_sensitive("myapp_sensitive_doc", "api_token").unwrap();
+/// let all = getall("myapp_sensitive_doc").unwrap();
+/// assert!(all.contains(&("api_token".to_string(), REDACTED.to_string())));
+/// ```
+///
+/// Marking a pattern that is already marked has no effect.
+///
+/// # Errors
+///
+/// Fails if the pattern is not a valid glob.
+pub fn mark_sensitive(app_name: impl Into<String>, pattern: &str) -> io::Result<()> {
+    let pattern = glob::Pattern::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut registry = SENSITIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let patterns = registry.get_or_insert_with(HashMap::new).entry(app_name.into()).or_default();
+    if !patterns.contains(&pattern) {
+        patterns.push(pattern);
+    }
+    Ok(())
+}
+
+/// Returns `true` if `key`, relative to the app's folder, was marked sensitive.
+pub fn is_sensitive(app_name: &str, key: &str) -> bool {
+    let registry = SENSITIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let patterns = match registry.as_ref().and_then(|registry| registry.get(app_name)) {
+        Some(patterns) => patterns,
+        None => return false,
+    };
+    patterns.iter().any(|pattern| crate::key_matches(pattern, key))
+}
+
+/// The value to list for `key`: `value` itself, or [`REDACTED`] if the key is sensitive.
+pub(crate) fn redact(app_name: &str, key: &str, value: String) -> String {
+    if is_sensitive(app_name, key) { REDACTED.to_string() } else { value }
+}
+
+/// Like `redact`, for a value that may be missing.
+pub(crate) fn redact_option(app_name: &str, key: &str, value: Option<String>) -> Option<String> {
+    value.map(|value| redact(app_name, key, value))
+}
+
+#[cfg(test)]
+mod sensitive_test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_sensitive_values_are_masked() {
+        let app_name = "prefstore_sensitive_test";
+        let _ = std::fs::remove_dir_all(config_folder_path(app_name).unwrap());
+        savepreference(app_name, "api_token", "s3cr3t").unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        savecustom(app_name, "secrets/password.txt", "hunter2").unwrap();
+        mark_sensitive(app_name, "api_token").unwrap();
+        mark_sensitive(app_name, "secrets/*").unwrap();
+
+        assert_eq!(getall(app_name).unwrap(), vec![
+            ("api_token".to_string(), REDACTED.to_string()),
+            ("password".to_string(), REDACTED.to_string()),
+            ("theme".to_string(), "dark".to_string()),
+        ]);
+        let keyed = getallcustomkeyed(app_name, "", &["txt"], Depth::Recursive).unwrap();
+        assert_eq!(keyed["secrets/password.txt"], REDACTED);
+        let read = readallcustom(app_name, "", &["txt"], Depth::Recursive).unwrap();
+        assert_eq!(read["api_token.txt"].as_ref().unwrap(), REDACTED);
+        assert_eq!(read["theme.txt"].as_ref().unwrap(), "dark");
+
+        let reveal = ReadOptions { reveal_sensitive: true };
+        let read = readallcustom_with(app_name, "", &["txt"], Depth::Recursive, &reveal).unwrap();
+        assert_eq!(read["api_token.txt"].as_ref().unwrap(), "s3cr3t");
+        let bytes = readallcustombytes_with(app_name, "secrets", &["txt"], Depth::Shallow, &reveal).unwrap();
+        assert_eq!(bytes["secrets/password.txt"].as_ref().unwrap(), b"hunter2");
+        assert!(getall_with(app_name, &reveal).unwrap().contains(&("api_token".to_string(), "s3cr3t".to_string())));
+
+        let store = Store::new(app_name).unwrap();
+        let masked: Vec<String> = store.iter("").unwrap().map(|entry| entry.unwrap().1).collect();
+        assert_eq!(masked, vec![REDACTED, REDACTED, "dark"]);
+        let revealed: Vec<String> = store.iter("").unwrap().reveal().map(|entry| entry.unwrap().1).collect();
+        assert_eq!(revealed, vec!["s3cr3t", "hunter2", "dark"]);
+        assert!(store.search("", "s3cr3t").unwrap().is_empty());
+
+        // Reading the key itself is not masked.
+        assert_eq!(getpreference(app_name, "api_token", ""), "s3cr3t");
+
+        let (_subscription, events) = observe_channel(app_name);
+        savepreference(app_name, "api_token", "t0k3n").unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!((event.old.as_deref(), event.new.as_deref()), (Some(REDACTED), Some(REDACTED)));
+        assert!(!format!("{:?}", event).contains("t0k3n"));
+    }
+}
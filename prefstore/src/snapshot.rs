@@ -0,0 +1,482 @@
+//! Copies of an app's whole folder that can be restored later.
+//!
+//! Snapshots are kept next to the app's folder, in a hidden folder named after the app,
+//! `.{app}.prefstore-snapshots`, so they never show up among the app's values. One is
+//! taken automatically before every migration and before `clearall`. Old snapshots are
+//! removed according to the app's [`Retention`], by default keeping the last 10.
+//!
+//! Files that are the same as in the previous snapshot are hard links to its copy rather
+//! than new copies, so snapshots of a mostly unchanged app take little space. Snapshot
+//! files are never written once taken, so sharing them is safe.
+
+use std::{collections::{HashMap, HashSet}, fs, io, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::encoding::{escape, unescape};
+
+const SNAPSHOTS_SUFFIX: &str = ".prefstore-snapshots";
+const INFO_FILE: &str = ".prefstore-snapshot";
+const STAGING_SUFFIX: &str = ".partial";
+
+static RETENTION: Mutex<Option<HashMap<String, Retention>>> = Mutex::new(None);
+
+/// Which snapshots of an app are kept when a new one is taken. The new snapshot is always
+/// kept.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{set_snapshot_retention, Retention};
+/// use std::time::Duration;
+///
+/// let week = Duration::from_secs(7 * 24 * 60 * 60);
+/// set_snapshot_retention("myapp", Retention::new().keep_last(20).max_age(week)).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    keep_last: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention::new()
+    }
+}
+
+impl Retention {
+    /// Keeps the last 10 snapshots.
+    pub fn new() -> Retention {
+        Retention { keep_last: Some(10), max_age: None }
+    }
+
+    /// Keeps every snapshot.
+    pub fn unlimited() -> Retention {
+        Retention { keep_last: None, max_age: None }
+    }
+
+    /// Keeps at most the last `count` snapshots.
+    pub fn keep_last(mut self, count: usize) -> Retention {
+        self.keep_last = Some(count);
+        self
+    }
+
+    /// Removes snapshots older than `age`.
+    pub fn max_age(mut self, age: Duration) -> Retention {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+/// Sets which snapshots of an app are kept, replacing any previous rules. The rules are
+/// applied the next time a snapshot is taken.
+///
+/// # Errors
+///
+/// Fails if there is no system configuration directory.
+pub fn set_snapshot_retention(app_name: impl Into<String>, retention: Retention) -> io::Result<()> {
+    let app_name = app_name.into();
+    crate::config_folder_path(&app_name)?;
+    RETENTION.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).insert(app_name, retention);
+    Ok(())
+}
+
+/// A saved copy of an app's folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    app_name: String,
+    id: String,
+    label: String,
+    time: SystemTime,
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    /// The app the snapshot belongs to.
+    pub fn app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    /// The name of the snapshot, unique among the app's snapshots.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The label given when the snapshot was taken.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// When the snapshot was taken, to the millisecond.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Brings the app's values back to how they were in the snapshot: values changed or
+    /// removed since are written back, and values added since are removed. Every change
+    /// is made through the prefstore API, so it reaches the app's observers, audit log and
+    /// history. The schema version recorded by migrations is restored too.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the snapshot was removed, or a value cannot be read or written.
+    pub fn restore(&self) -> io::Result<()> {
+        if !self.dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("snapshot {} no longer exists", self.id)));
+        }
+        restore_values(&self.app_name, &self.dir)
+    }
+
+    /// Removes the snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the snapshot cannot be removed.
+    pub fn delete(self) -> io::Result<()> {
+        ignore_not_found(fs::remove_dir_all(&self.dir))
+    }
+}
+
+/// Copies the app's folder into a new snapshot, then removes old snapshots according to
+/// the app's [`Retention`]. Files unchanged since the previous snapshot are linked to it
+/// instead of copied.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::{list_snapshots, savepreference, snapshot, getpreference};
+///
+/// savepreference("myapp_snapshot_doc", "theme", "dark").unwrap();
+/// let before = snapshot("myapp_snapshot_doc", "before settings import").unwrap();
+/// savepreference("myapp_snapshot_doc", "theme", "neon").unwrap();
+/// before.restore().unwrap();
+/// assert_eq!(getpreference("myapp_snapshot_doc", "theme", ""), "dark");
+/// assert!(list_snapshots("myapp_snapshot_doc").unwrap().contains(&before));
+/// ```
+///
+/// # Errors
+///
+/// Fails if the app's folder cannot be read or the snapshot cannot be written.
+pub fn snapshot(app_name: impl Into<String>, label: &str) -> io::Result<Snapshot> {
+    let app_name = app_name.into();
+    let app_dir = crate::config_folder_path(&app_name)?;
+    let root = snapshots_dir(&app_dir)?;
+    if !root.is_dir() {
+        crate::permissions::create_dirs(&root)?;
+        // Snapshots hold the app's values, so they get the same access as its folder.
+        if let Ok(metadata) = fs::metadata(&app_dir) {
+            fs::set_permissions(&root, metadata.permissions())?;
+        }
+    }
+
+    let time = SystemTime::now();
+    let millis = time.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let mut id = format!("{:013}", millis);
+    for n in 1.. {
+        if !root.join(&id).exists() && !root.join(format!("{}{}", id, STAGING_SUFFIX)).exists() {
+            break;
+        }
+        id = format!("{:013}-{}", millis, n);
+    }
+    let time = UNIX_EPOCH + Duration::from_millis(millis as u64);
+    let previous = list_snapshots(&app_name)?.pop().map(|snapshot| snapshot.dir);
+
+    let staging = root.join(format!("{}{}", id, STAGING_SUFFIX));
+    let result = (|| {
+        fs::create_dir(&staging)?;
+        if app_dir.is_dir() {
+            copy_linked(&app_dir, &staging, previous.as_deref())?;
+        }
+        crate::atomic_write(&staging.join(INFO_FILE), format!("{}\t{}\n", millis, escape(label)).as_bytes())?;
+        fs::rename(&staging, root.join(&id))
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    let dir = root.join(&id);
+    let taken = Snapshot { app_name: app_name.clone(), id, label: label.to_string(), time, dir };
+    prune(&app_name, &taken)?;
+    Ok(taken)
+}
+
+/// Returns the app's snapshots, oldest first.
+///
+/// # Errors
+///
+/// Fails if the snapshots cannot be read.
+pub fn list_snapshots(app_name: impl Into<String>) -> io::Result<Vec<Snapshot>> {
+    let app_name = app_name.into();
+    let root = snapshots_dir(&crate::config_folder_path(&app_name)?)?;
+    let entries = match fs::read_dir(&root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let id = match entry.file_name().into_string() {
+            Ok(id) if !id.ends_with(STAGING_SUFFIX) && entry.file_type()?.is_dir() => id,
+            _ => continue,
+        };
+        let info = match fs::read_to_string(entry.path().join(INFO_FILE)) {
+            Ok(info) => info,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let (time, label) = parse_info(&info)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed snapshot {}", id)))?;
+        snapshots.push(Snapshot { app_name: app_name.clone(), id, label, time, dir: entry.path() });
+    }
+    snapshots.sort_by(|a, b| (a.time, &a.id).cmp(&(b.time, &b.id)));
+    Ok(snapshots)
+}
+
+/// Copies the app's folder like `migrate::copy_tree`, but hard-links the files that
+/// `previous`, the previous snapshot, holds with the same contents. Queues are left out.
+fn copy_linked(from: &Path, to: &Path, previous: Option<&Path>) -> io::Result<()> {
+    crate::migrate::copy_tree_with(from, to, &|source, target, relative| {
+        if source.parent().is_some_and(crate::queue::is_queue_dir) {
+            return Ok(());
+        }
+        if let Some(earlier) = previous.map(|previous| previous.join(relative)) {
+            if same_contents(source, &earlier)? && fs::hard_link(&earlier, target).is_ok() {
+                return Ok(());
+            }
+        }
+        fs::copy(source, target).map(|_| ())
+    })
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    match fs::metadata(b) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == fs::metadata(a)?.len() => Ok(fs::read(a)? == fs::read(b)?),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes a snapshot before an operation that changes many values, if the app has any.
+pub(crate) fn snapshot_before(app_name: &str, operation: &str) -> io::Result<()> {
+    if crate::config_folder_path(app_name)?.is_dir() {
+        snapshot(app_name, &format!("before {}", operation))?;
+    }
+    Ok(())
+}
+
+fn snapshots_dir(app_dir: &Path) -> io::Result<PathBuf> {
+    let name = app_dir.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "app folder has no name"))?
+        .to_string_lossy();
+    Ok(app_dir.with_file_name(format!(".{}{}", name, SNAPSHOTS_SUFFIX)))
+}
+
+fn parse_info(info: &str) -> Option<(SystemTime, String)> {
+    let (millis, label) = info.trim_end_matches('\n').split_once('\t')?;
+    Some((UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?), unescape(label)?))
+}
+
+/// Removes the snapshots of the app that its retention rules no longer keep, except `taken`.
+fn prune(app_name: &str, taken: &Snapshot) -> io::Result<()> {
+    let retention = RETENTION.lock().unwrap_or_else(|e| e.into_inner())
+        .as_ref().and_then(|retention| retention.get(app_name).copied()).unwrap_or_default();
+    let snapshots = list_snapshots(app_name)?;
+    let excess = retention.keep_last.map_or(0, |keep| snapshots.len().saturating_sub(keep));
+    let now = SystemTime::now();
+    for (index, snapshot) in snapshots.into_iter().enumerate() {
+        let too_old = retention.max_age.is_some_and(|age| now.duration_since(snapshot.time).is_ok_and(|elapsed| elapsed > age));
+        if snapshot != *taken && (index < excess || too_old) {
+            snapshot.delete()?;
+        }
+    }
+    Ok(())
+}
+
+/// Brings the values of the app back to those in `saved_dir`, a copy of its folder. Every
+/// change is made through the prefstore API, and the schema version is restored too.
+pub(crate) fn restore_values(app_name: &str, saved_dir: &Path) -> io::Result<()> {
+    let app_dir = crate::config_folder_path(app_name)?;
+    let saved = data_files(saved_dir)?;
+    for key in &saved {
+        let (from, to) = (saved_dir.join(key), app_dir.join(key));
+        let contents = fs::read(&from)?;
+        if fs::read(&to).ok().as_deref() == Some(contents.as_slice()) {
+            continue;
+        }
+        if let Some(parent) = to.parent() {
+            crate::permissions::create_dirs(parent)?;
+        }
+        crate::tracked_write(app_name, &to, || crate::atomic_write(&to, &contents))?;
+    }
+    let saved: HashSet<&String> = saved.iter().collect();
+    for key in data_files(&app_dir)? {
+        if !saved.contains(&key) {
+            let path = app_dir.join(&key);
+            ignore_not_found(crate::tracked_write(app_name, &path, || fs::remove_file(&path)))?;
+            // Remove the folders the key leaves empty, as long as the saved copy lacks them.
+            for dir in path.ancestors().skip(1).take_while(|dir| *dir != app_dir) {
+                if saved_dir.join(dir.strip_prefix(&app_dir).unwrap_or(dir)).is_dir() || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let version = crate::migrate::VERSION_FILE;
+    match fs::read(saved_dir.join(version)) {
+        Ok(contents) => crate::atomic_write(&app_dir.join(version), &contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => ignore_not_found(fs::remove_file(app_dir.join(version))),
+        Err(e) => Err(e),
+    }
+}
+
+/// The keys of the values in `dir`: every file that is neither hidden, inside a hidden
+/// folder or a queue, nor a symbolic link.
+pub(crate) fn data_files(dir: &Path) -> io::Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, keys: &mut Vec<String>) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if !crate::queue::is_queue_dir(&entry.path()) {
+                    walk(&entry.path(), &format!("{}{}/", prefix, name), keys)?;
+                }
+            } else if file_type.is_file() {
+                keys.push(format!("{}{}", prefix, name));
+            }
+        }
+        Ok(())
+    }
+    let mut keys = Vec::new();
+    walk(dir, "", &mut keys)?;
+    keys.sort();
+    Ok(keys)
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod snapshot_test {
+    use super::*;
+    use crate::*;
+
+    fn fresh(app_name: &str) -> &str {
+        let app_dir = config_folder_path(app_name).unwrap();
+        let _ = fs::remove_dir_all(snapshots_dir(&app_dir).unwrap());
+        let _ = fs::remove_dir_all(app_dir);
+        app_name
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let app_name = fresh("prefstore_snapshot_test");
+        savepreference(app_name, "theme", "dark").unwrap();
+        savepreference(app_name, "window/width", 800).unwrap();
+        let saved = snapshot(app_name, "first\trun").unwrap();
+
+        savepreference(app_name, "theme", "light").unwrap();
+        clearpreference(app_name, "window/width").unwrap();
+        savecustom(app_name, "notes.md", "new").unwrap();
+        let (_subscription, events) = observe_channel(app_name);
+        saved.restore().unwrap();
+
+        assert_eq!(getall(app_name).unwrap(), vec![
+            ("theme".to_string(), "dark".to_string()),
+            ("width".to_string(), "800".to_string()),
+        ]);
+        assert!(!config_folder_path(app_name).unwrap().join("notes.md").exists());
+        assert_eq!(events.try_iter().count(), 3);
+        assert_eq!(list_snapshots(app_name).unwrap(), vec![saved.clone()]);
+        assert_eq!(saved.label(), "first\trun");
+
+        saved.clone().delete().unwrap();
+        assert!(list_snapshots(app_name).unwrap().is_empty());
+        assert_eq!(saved.restore().unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_automatic_snapshots_and_retention() {
+        let app_name = fresh("prefstore_snapshot_auto_test");
+        set_snapshot_retention(app_name, Retention::new().keep_last(2)).unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        clearall(app_name, "txt").unwrap();
+        let labels: Vec<String> = list_snapshots(app_name).unwrap().iter().map(|s| s.label().to_string()).collect();
+        assert_eq!(labels, vec!["before clearall"]);
+        list_snapshots(app_name).unwrap()[0].restore().unwrap();
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+
+        let store = Store::new(app_name).unwrap();
+        store.migrate(&Migrations::new().migration(Migration::new(1).rename("theme", "color_scheme"))).unwrap();
+        snapshot(app_name, "manual").unwrap();
+        let labels: Vec<String> = list_snapshots(app_name).unwrap().iter().map(|s| s.label().to_string()).collect();
+        assert_eq!(labels, vec!["before migration to version 1", "manual"]);
+
+        // Restoring from before the migration brings back the old version too.
+        list_snapshots(app_name).unwrap()[0].restore().unwrap();
+        assert_eq!(store.schema_version().unwrap(), 0);
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+        assert!(!store.dir().join("color_scheme.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unchanged_files_are_shared() {
+        use std::os::unix::fs::MetadataExt;
+        let app_name = fresh("prefstore_snapshot_link_test");
+        set_snapshot_retention(app_name, Retention::unlimited()).unwrap();
+        savepreference(app_name, "theme", "dark").unwrap();
+        savecustom(app_name, "layouts/main.json", "{}").unwrap();
+        let first = snapshot(app_name, "first").unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        let second = snapshot(app_name, "second").unwrap();
+
+        let inode = |snapshot: &Snapshot, key: &str| fs::metadata(snapshot.dir.join(key)).unwrap().ino();
+        assert_eq!(inode(&first, "layouts/main.json"), inode(&second, "layouts/main.json"));
+        assert_ne!(inode(&first, "theme.txt"), inode(&second, "theme.txt"));
+
+        // Deleting one snapshot leaves the shared file to the other.
+        first.delete().unwrap();
+        second.restore().unwrap();
+        assert_eq!(getcustom(app_name, "layouts/main.json", "").unwrap(), "{}");
+        assert_eq!(getpreference(app_name, "theme", ""), "light");
+    }
+
+    #[test]
+    fn test_queues_are_left_out() {
+        let app_name = fresh("prefstore_snapshot_queue_test");
+        savepreference(app_name, "theme", "dark").unwrap();
+        let queue = Queue::open(app_name, "jobs").unwrap();
+        queue.enqueue("first").unwrap();
+        let saved = snapshot(app_name, "with a queue").unwrap();
+        assert_eq!(data_files(&saved.dir).unwrap(), vec!["theme.txt"]);
+
+        let lease = queue.dequeue().unwrap().unwrap();
+        queue.ack(lease).unwrap();
+        queue.enqueue("second").unwrap();
+        savepreference(app_name, "theme", "light").unwrap();
+        let (_subscription, events) = observe_channel(app_name);
+        saved.restore().unwrap();
+
+        assert_eq!(getpreference(app_name, "theme", ""), "dark");
+        let keys: Vec<String> = events.try_iter().map(|event| event.key).collect();
+        assert_eq!(keys, vec!["theme.txt"]);
+        assert_eq!(queue.len().unwrap(), 1);
+        assert_eq!(queue.dequeue().unwrap().unwrap().value, "second");
+    }
+}
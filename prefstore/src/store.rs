@@ -0,0 +1,276 @@
+//! A handle on one app's preferences, and a lazy iterator over them.
+
+use std::{fs, io, path::{Path, PathBuf}};
+
+/// A handle on the preferences of one app, stored in its folder under the system
+/// configuration directory.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::Store;
+///
+/// let store = Store::new("myapp").unwrap();
+/// for entry in store.iter("").unwrap().extension("txt") {
+///     let (key, value) = entry.unwrap();
+///     println!("{}: {}", key, value);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Store {
+    app_name: String,
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Creates a handle on the preferences of the given app. Nothing is created on disk.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the system has no configuration directory.
+    pub fn new(app_name: impl Into<String>) -> io::Result<Store> {
+        let app_name = app_name.into();
+        let dir = crate::config_folder_path(&app_name)?;
+        Ok(Store { app_name, dir })
+    }
+
+    /// The name of the app this store belongs to.
+    pub fn app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    /// The folder holding the app's preferences.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns a lazy iterator over the keys and values stored in a namespace, including its
+    /// sub-directories. Use `""` for the whole store.
+    ///
+    /// Files are read one at a time as the iterator advances, so callers can stop early
+    /// and memory use does not grow with the size of the store. Entries come in key order
+    /// and hidden files are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the namespace is absolute or contains `..`. Errors reading individual
+    /// entries are yielded by the iterator.
+    pub fn iter(&self, namespace: &str) -> io::Result<Entries> {
+        let root = crate::namespace_path(&self.app_name, namespace)?;
+        Ok(Entries {
+            app_name: self.app_name.clone(),
+            app_dir: self.dir.clone(),
+            stack: vec![vec![Ok(root)].into_iter()],
+            prefix: None,
+            pattern: None,
+            extensions: Vec::new(),
+            reveal: false,
+        })
+    }
+}
+
+/// Iterator over the `(key, value)` pairs of a namespace, created by [`Store::iter`].
+///
+/// Keys are paths relative to the app's folder, as accepted by `getcustom`. Values are
+/// read as text, with invalid UTF-8 replaced. Values of keys marked with
+/// [`mark_sensitive`](crate::mark_sensitive) read as [`REDACTED`](crate::REDACTED) unless
+/// [`Entries::reveal`] is called.
+#[derive(Debug)]
+pub struct Entries {
+    app_name: String,
+    app_dir: PathBuf,
+    /// The sorted, not yet visited children of every directory being walked.
+    stack: Vec<std::vec::IntoIter<io::Result<PathBuf>>>,
+    prefix: Option<String>,
+    pattern: Option<glob::Pattern>,
+    extensions: Vec<String>,
+    reveal: bool,
+}
+
+impl Entries {
+    /// Yields the real values of sensitive keys instead of masking them.
+    pub fn reveal(mut self) -> Entries {
+        self.reveal = true;
+        self
+    }
+
+    /// Only yields keys that start with `prefix`. Directories that cannot contain such
+    /// keys are not walked.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Entries {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only yields keys matching a glob pattern such as `"logs/*.log"`. `*` does not match
+    /// `/`; use `**` to match across directories.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pattern is not a valid glob.
+    pub fn matching(mut self, pattern: &str) -> io::Result<Entries> {
+        let pattern = glob::Pattern::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.pattern = Some(pattern);
+        Ok(self)
+    }
+
+    /// Only yields keys with the given extension. Call it several times to allow several
+    /// extensions.
+    pub fn extension(mut self, extension: impl Into<String>) -> Entries {
+        self.extensions.push(extension.into());
+        self
+    }
+
+    fn key_of(&self, path: &Path) -> String {
+        path.strip_prefix(&self.app_dir)
+            .unwrap_or(path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Returns `false` if nothing below the directory `key` can match the prefix.
+    fn may_contain(&self, key: &str) -> bool {
+        match &self.prefix {
+            Some(prefix) => {
+                let dir = format!("{}/", key);
+                key.is_empty() || dir.starts_with(prefix.as_str()) || prefix.starts_with(&dir)
+            },
+            None => true,
+        }
+    }
+
+    fn accepts(&self, key: &str, path: &Path) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+            if !pattern.matches_with(key, options) {
+                return false;
+            }
+        }
+        if !self.extensions.is_empty() {
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            if !self.extensions.iter().any(|e| e == extension) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The sorted children of `dir`, skipping hidden files. A missing directory is empty.
+fn children(dir: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut children = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                if !entry.file_name().to_string_lossy().starts_with('.') {
+                    children.push(Ok(entry.path()));
+                }
+            },
+            Err(e) => children.push(Err(e)),
+        }
+    }
+    children.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => a.cmp(b),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+    Ok(children)
+}
+
+impl Iterator for Entries {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match self.stack.last_mut()?.next() {
+                Some(next) => next,
+                None => {
+                    self.stack.pop();
+                    continue;
+                },
+            };
+            let path = match next {
+                Ok(path) => path,
+                Err(e) => return Some(Err(e)),
+            };
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            let key = self.key_of(&path);
+            if metadata.is_dir() {
+                if self.may_contain(&key) {
+                    match children(&path) {
+                        Ok(children) => self.stack.push(children.into_iter()),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            } else if self.accepts(&key, &path) {
+                return Some(match fs::read(&path) {
+                    Ok(bytes) => crate::verify_integrity(&path, &bytes).map(|_| {
+                        let value = String::from_utf8_lossy(&bytes).into_owned();
+                        let value = if self.reveal { value } else { crate::sensitive::redact(&self.app_name, &key, value) };
+                        (key, value)
+                    }),
+                    Err(e) => Err(e),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod store_test {
+    use super::*;
+
+    fn test_store() -> Store {
+        let store = Store::new("prefstore_store_test").unwrap();
+        let _ = fs::remove_dir_all(store.dir());
+        crate::savecustom("prefstore_store_test", "theme.txt", "dark").unwrap();
+        crate::savecustom("prefstore_store_test", "logs/2024-01-02.log", "two").unwrap();
+        crate::savecustom("prefstore_store_test", "logs/2024-01-01.log", "one").unwrap();
+        crate::savecustom("prefstore_store_test", "logs/old/2023-12-31.log", "old").unwrap();
+        crate::savecustom("prefstore_store_test", "logs/.lock", "").unwrap();
+        store
+    }
+
+    fn keys(entries: Entries) -> Vec<String> {
+        entries.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_iter_filters() {
+        let store = test_store();
+        assert_eq!(keys(store.iter("").unwrap()), vec![
+            "logs/2024-01-01.log", "logs/2024-01-02.log", "logs/old/2023-12-31.log", "theme.txt",
+        ]);
+        assert_eq!(keys(store.iter("logs").unwrap().matching("logs/*.log").unwrap()), vec![
+            "logs/2024-01-01.log", "logs/2024-01-02.log",
+        ]);
+        assert_eq!(keys(store.iter("").unwrap().prefix("logs/2024")), vec![
+            "logs/2024-01-01.log", "logs/2024-01-02.log",
+        ]);
+        assert_eq!(keys(store.iter("").unwrap().extension("txt")), vec!["theme.txt"]);
+        assert!(keys(store.iter("missing").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_iter_stops_early() {
+        let store = test_store();
+        let first = store.iter("logs").unwrap().next().unwrap().unwrap();
+        assert_eq!(first, ("logs/2024-01-01.log".to_string(), "one".to_string()));
+    }
+}
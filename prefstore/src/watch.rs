@@ -0,0 +1,378 @@
+//! Watching an app's preferences for changes made outside the running process.
+//!
+//! A watcher runs a background thread that keeps a snapshot of the watched key or
+//! namespace: the size and timestamps of every file, never the contents. Whenever
+//! something may have changed it rescans, compares against the snapshot and reports the
+//! difference. With the `watch` feature rescans are triggered by filesystem
+//! notifications (inotify on Linux); without it, or if notifications cannot be set up,
+//! the thread polls.
+//!
+//! Hidden files are never reported, which covers the temporary files prefstore writes
+//! before renaming them into place.
+
+use std::{collections::BTreeMap, fs, io, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant, SystemTime}};
+
+/// How often the background thread checks whether it has been stopped.
+const TICK: Duration = Duration::from_millis(25);
+
+/// What happened to a watched key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The key did not exist before.
+    Created,
+    /// The key's value changed.
+    Modified,
+    /// The key was removed.
+    Deleted,
+}
+
+/// A change to a watched key, reported by [`watch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// The key that changed, relative to the app's folder.
+    pub key: String,
+    /// Whether the key was created, modified or deleted.
+    pub kind: ChangeKind,
+    /// The new value, or `None` if the key was deleted or could not be read. When a
+    /// namespace is watched, the values of keys marked with
+    /// [`mark_sensitive`](crate::mark_sensitive) are reported as [`REDACTED`](crate::REDACTED).
+    pub value: Option<String>,
+}
+
+/// Settings for [`watch_with`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchOptions {
+    /// How long the files must stay quiet after a notification before changes are
+    /// reported, so a burst of writes is reported once.
+    pub debounce: Duration,
+    /// How often to rescan when filesystem notifications are not available.
+    pub poll_interval: Duration,
+    /// Always poll, even when filesystem notifications are available.
+    pub force_polling: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            debounce: Duration::from_millis(100),
+            poll_interval: Duration::from_secs(1),
+            force_polling: false,
+        }
+    }
+}
+
+/// A running watch. Dropping it, or calling [`Watcher::stop`], stops the watch.
+#[derive(Debug)]
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watcher {
+    /// Stops the watch and waits for the background thread to finish. No callbacks run
+    /// after this returns.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// What a watch covers: a single key, or every key in a namespace.
+#[derive(Debug, Clone)]
+enum Target {
+    Key { app_dir: PathBuf, path: PathBuf },
+    Namespace { app_name: String, namespace: String, dir: PathBuf },
+}
+
+/// The parts of a file's metadata that change when it is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    size: u64,
+    modified: Option<SystemTime>,
+    created: Option<SystemTime>,
+}
+
+impl Target {
+    fn scan(&self) -> io::Result<BTreeMap<String, (PathBuf, Stamp)>> {
+        let mut snapshot = BTreeMap::new();
+        match self {
+            Target::Key { app_dir, path } => match fs::metadata(path) {
+                Ok(metadata) if metadata.is_file() => {
+                    let stamp = Stamp { size: metadata.len(), modified: metadata.modified().ok(), created: metadata.created().ok() };
+                    snapshot.insert(crate::relative_key(app_dir, path)?, (path.clone(), stamp));
+                },
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            },
+            Target::Namespace { app_name, namespace, .. } => {
+                for info in crate::list_keys(app_name.as_str(), namespace)? {
+                    let stamp = Stamp { size: info.size, modified: info.modified, created: info.created };
+                    snapshot.insert(info.key, (info.path, stamp));
+                }
+            },
+        }
+        Ok(snapshot)
+    }
+
+    /// The directory to subscribe to, and whether its sub-directories matter.
+    #[cfg(feature = "watch")]
+    fn watch_root(&self) -> (&Path, bool) {
+        match self {
+            Target::Key { path, .. } => (path.parent().unwrap_or(path), false),
+            Target::Namespace { dir, .. } => (dir, true),
+        }
+    }
+
+    /// The value to report for `key`, masked if a namespace is watched and the key is
+    /// sensitive. Watching the key itself is an explicit request for its value.
+    fn value(&self, key: &str, path: &Path) -> Option<String> {
+        let value = read_value(path);
+        match self {
+            Target::Key { .. } => value,
+            Target::Namespace { app_name, .. } => crate::sensitive::redact_option(app_name, key, value),
+        }
+    }
+
+    fn covers(&self, path: &Path) -> bool {
+        let hidden = path.file_name().map(|name| name.to_string_lossy().starts_with('.')).unwrap_or(false);
+        !hidden && match self {
+            Target::Key { path: key_path, .. } => path == key_path,
+            Target::Namespace { dir, .. } => path.starts_with(dir),
+        }
+    }
+}
+
+fn read_value(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Compares two snapshots. Keys in `touched` are reported as modified even when their
+/// metadata looks unchanged, since timestamps can be too coarse to tell two writes apart.
+fn diff(target: &Target, old: &BTreeMap<String, (PathBuf, Stamp)>, new: &BTreeMap<String, (PathBuf, Stamp)>, touched: &[PathBuf]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, (path, stamp)) in new {
+        let kind = match old.get(key) {
+            None => ChangeKind::Created,
+            Some((_, old_stamp)) if old_stamp != stamp || touched.contains(path) => ChangeKind::Modified,
+            Some(_) => continue,
+        };
+        changes.push(Change { key: key.clone(), kind, value: target.value(key, path) });
+    }
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            changes.push(Change { key: key.clone(), kind: ChangeKind::Deleted, value: None });
+        }
+    }
+    changes
+}
+
+/// Watches a key or a namespace of an app's preferences and calls `callback` for every
+/// key that is created, modified or deleted, with default [`WatchOptions`].
+///
+/// If `key_or_namespace` names an existing directory, every key inside it, including its
+/// sub-directories, is watched; use `""` for the whole app. Otherwise it is taken as a key
+/// such as `"theme.txt"`, which does not need to exist yet.
+///
+/// # Examples
+///
+/// ```
+/// use prefstore::watch;
+///
+/// let watcher = watch("myapp", "theme.txt", |change| {
+///     println!("{} {:?}: {:?}", change.key, change.kind, change.value);
+/// }).unwrap();
+/// // ...
+/// watcher.stop();
+/// ```
+pub fn watch<F>(app_name: impl Into<String>, key_or_namespace: &str, callback: F) -> io::Result<Watcher>
+where F: FnMut(Change) + Send + 'static {
+    watch_with(app_name, key_or_namespace, WatchOptions::default(), callback)
+}
+
+/// Like [`watch`], with explicit [`WatchOptions`].
+///
+/// # Errors
+///
+/// Fails if the key or namespace contains `..` or is absolute, or if the initial scan
+/// fails. Errors during later rescans are retried on the next rescan.
+pub fn watch_with<F>(app_name: impl Into<String>, key_or_namespace: &str, options: WatchOptions, mut callback: F) -> io::Result<Watcher>
+where F: FnMut(Change) + Send + 'static {
+    let app_name = app_name.into();
+    let app_dir = crate::config_folder_path(&app_name)?;
+    let path = crate::namespace_path(&app_name, key_or_namespace)?;
+    let target = if key_or_namespace.is_empty() || path.is_dir() {
+        Target::Namespace { app_name, namespace: key_or_namespace.to_string(), dir: path }
+    } else {
+        Target::Key { app_dir, path }
+    };
+
+    let mut snapshot = target.scan()?;
+    let events = if options.force_polling { None } else { subscribe(&target)? };
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+
+    let thread = thread::Builder::new().name("prefstore-watch".to_string()).spawn(move || {
+        let (_subscription, events) = match events {
+            Some((subscription, events)) => (Some(subscription), Some(events)),
+            None => (None, None),
+        };
+        let mut touched: Vec<PathBuf> = Vec::new();
+        let mut last_event: Option<Instant> = None;
+        let mut last_scan = Instant::now();
+
+        while !thread_stop.load(Ordering::Relaxed) {
+            let due = match &events {
+                Some(events) => {
+                    match events.recv_timeout(TICK) {
+                        Ok(paths) => {
+                            for path in paths.into_iter().filter(|path| target.covers(path)) {
+                                if !touched.contains(&path) {
+                                    touched.push(path);
+                                }
+                                last_event = Some(Instant::now());
+                            }
+                        },
+                        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {},
+                        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                    last_event.map(|at| at.elapsed() >= options.debounce).unwrap_or(false)
+                },
+                None => {
+                    thread::sleep(TICK);
+                    last_scan.elapsed() >= options.poll_interval
+                },
+            };
+            if !due {
+                continue;
+            }
+
+            last_scan = Instant::now();
+            last_event = None;
+            let Ok(new_snapshot) = target.scan() else { continue };
+            for change in diff(&target, &snapshot, &new_snapshot, &touched) {
+                if thread_stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                callback(change);
+            }
+            snapshot = new_snapshot;
+            touched.clear();
+        }
+    })?;
+
+    Ok(Watcher { stop, thread: Some(thread) })
+}
+
+/// Subscribes to filesystem notifications for the target, creating the watched folder
+/// if needed. Returns the subscription, which must be kept alive, and a channel of
+/// changed paths, or `None` if notifications are unavailable and the watch has to poll.
+#[cfg(feature = "watch")]
+fn subscribe(target: &Target) -> io::Result<Option<(notify::RecommendedWatcher, std::sync::mpsc::Receiver<Vec<PathBuf>>)>> {
+    use notify::Watcher as _;
+
+    let (root, recursive) = target.watch_root();
+    crate::permissions::create_dirs(root)?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mode = if recursive { notify::RecursiveMode::Recursive } else { notify::RecursiveMode::NonRecursive };
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = sender.send(event.paths);
+        }
+    }).and_then(|mut watcher| watcher.watch(root, mode).map(|_| watcher));
+    Ok(watcher.ok().map(|watcher| (watcher, receiver)))
+}
+
+#[cfg(not(feature = "watch"))]
+fn subscribe(_target: &Target) -> io::Result<Option<((), std::sync::mpsc::Receiver<Vec<PathBuf>>)>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod watch_test {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    const APP: &str = "prefstore_watch_test";
+
+    fn next(changes: &Receiver<Change>) -> Change {
+        changes.recv_timeout(Duration::from_secs(5)).expect("no change reported")
+    }
+
+    fn check_watch(namespace: &str, options: WatchOptions) {
+        let dir = crate::config_folder_path(APP).unwrap().join(namespace);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let (sender, changes) = channel();
+        let watcher = watch_with(APP, namespace, options, move |change| sender.send(change).unwrap()).unwrap();
+
+        let key = format!("{}/theme.txt", namespace);
+        crate::savecustom(APP, &key, "dark").unwrap();
+        assert_eq!(next(&changes), Change { key: key.clone(), kind: ChangeKind::Created, value: Some("dark".to_string()) });
+
+        crate::savecustom(APP, &key, "light").unwrap();
+        assert_eq!(next(&changes), Change { key: key.clone(), kind: ChangeKind::Modified, value: Some("light".to_string()) });
+
+        crate::clearcustom(APP, &key);
+        assert_eq!(next(&changes), Change { key: key.clone(), kind: ChangeKind::Deleted, value: None });
+
+        watcher.stop();
+        assert!(changes.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_watch_polling() {
+        check_watch("polled", WatchOptions { poll_interval: Duration::from_millis(50), force_polling: true, ..Default::default() });
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_watch_notify() {
+        check_watch("notified", WatchOptions { debounce: Duration::from_millis(50), ..Default::default() });
+    }
+
+    #[test]
+    fn test_watch_single_key() {
+        crate::clearcustom(APP, "single.txt");
+        let (sender, changes) = channel();
+        let options = WatchOptions { poll_interval: Duration::from_millis(50), force_polling: true, ..Default::default() };
+        let _watcher = watch_with(APP, "single.txt", options, move |change| sender.send(change).unwrap()).unwrap();
+        crate::savecustom(APP, "other.txt", "ignored").unwrap();
+        crate::savecustom(APP, "single.txt", "watched").unwrap();
+        assert_eq!(next(&changes).key, "single.txt");
+    }
+
+    #[test]
+    fn test_sensitive_values_are_masked_in_namespaces() {
+        let dir = crate::config_folder_path(APP).unwrap().join("secrets");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        crate::mark_sensitive(APP, "secrets/*").unwrap();
+        let options = WatchOptions { poll_interval: Duration::from_millis(50), force_polling: true, ..Default::default() };
+
+        let (sender, changes) = channel();
+        let _watcher = watch_with(APP, "secrets", options, move |change| sender.send(change).unwrap()).unwrap();
+        crate::savecustom(APP, "secrets/token.txt", "s3cr3t").unwrap();
+        assert_eq!(next(&changes).value.as_deref(), Some(crate::REDACTED));
+
+        let (sender, changes) = channel();
+        let _watcher = watch_with(APP, "secrets/token.txt", options, move |change| sender.send(change).unwrap()).unwrap();
+        crate::savecustom(APP, "secrets/token.txt", "n3w").unwrap();
+        assert_eq!(next(&changes).value.as_deref(), Some("n3w"));
+    }
+}
@@ -0,0 +1,169 @@
+//! `#[derive(Preferences)]` for prefstore. Use it through prefstore's `derive` feature,
+//! which re-exports the macro next to the `Preferences` trait it implements.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error, Fields, Ident, Lit, LitStr, Result, Type};
+
+/// Implements `prefstore::Preferences` and `prefstore::FromPreferences` for a struct with
+/// named fields, and adds `get_<field>` and `set_<field>` functions for every field.
+///
+/// See the documentation of the `Preferences` trait for the supported `#[pref(...)]`
+/// attributes.
+#[proc_macro_derive(Preferences, attributes(pref))]
+pub fn derive_preferences(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// The options given in `#[pref(...)]` attributes.
+#[derive(Default)]
+struct PrefAttrs {
+    key: Option<LitStr>,
+    default: Option<String>,
+    namespace: Option<LitStr>,
+    sensitive: bool,
+}
+
+impl PrefAttrs {
+    fn parse(attrs: &[Attribute], on_field: bool) -> Result<PrefAttrs> {
+        let mut parsed = PrefAttrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("pref")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("namespace") {
+                    parsed.namespace = Some(meta.value()?.parse()?);
+                } else if on_field && meta.path.is_ident("key") {
+                    parsed.key = Some(meta.value()?.parse()?);
+                } else if on_field && meta.path.is_ident("default") {
+                    parsed.default = Some(default_text(&meta.value()?.parse()?)?);
+                } else if on_field && meta.path.is_ident("sensitive") {
+                    parsed.sensitive = true;
+                } else {
+                    return Err(meta.error("unsupported pref attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// The text a default literal is parsed from at runtime.
+fn default_text(lit: &Lit) -> Result<String> {
+    match lit {
+        Lit::Str(lit) => Ok(lit.value()),
+        Lit::Char(lit) => Ok(lit.value().to_string()),
+        Lit::Int(lit) => Ok(lit.base10_digits().to_string()),
+        Lit::Float(lit) => Ok(lit.base10_digits().to_string()),
+        Lit::Bool(lit) => Ok(lit.value.to_string()),
+        _ => Err(Error::new(lit.span(), "default must be a string, char, number or bool literal")),
+    }
+}
+
+/// Joins a namespace and a key the way prefstore names keys inside namespaces.
+fn full_key(namespace: Option<&LitStr>, key: &str, span: Span) -> Result<String> {
+    if key.is_empty() {
+        return Err(Error::new(span, "pref key cannot be empty"));
+    }
+    match namespace.map(LitStr::value) {
+        Some(namespace) if !namespace.trim_matches('/').is_empty() => Ok(format!("{}/{}", namespace.trim_matches('/'), key)),
+        _ => Ok(key.to_string()),
+    }
+}
+
+struct Field<'a> {
+    ident: &'a Ident,
+    ty: &'a Type,
+    key: String,
+    default: Option<String>,
+    sensitive: bool,
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(input.span(), "Preferences can only be derived for structs with named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "Preferences can only be derived for structs")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(input.generics.span(), "Preferences cannot be derived for generic structs"));
+    }
+    let container = PrefAttrs::parse(&input.attrs, false)?;
+
+    let mut fields = Vec::new();
+    for field in named {
+        let ident = field.ident.as_ref().expect("named field");
+        let attrs = PrefAttrs::parse(&field.attrs, true)?;
+        let key = attrs.key.as_ref().map(LitStr::value).unwrap_or_else(|| ident.to_string().trim_start_matches("r#").to_string());
+        let namespace = attrs.namespace.as_ref().or(container.namespace.as_ref());
+        let key = full_key(namespace, &key, field.span())?;
+        if fields.iter().any(|other: &Field| other.key == key) {
+            return Err(Error::new(field.span(), format!("two fields are stored under the key {:?}", key)));
+        }
+        fields.push(Field { ident, ty: &field.ty, key, default: attrs.default, sensitive: attrs.sensitive });
+    }
+
+    let keys: Vec<&String> = fields.iter().map(|field| &field.key).collect();
+    let sensitive_keys: Vec<&String> = fields.iter().filter(|field| field.sensitive).map(|field| &field.key).collect();
+    let sensitive: Vec<bool> = fields.iter().map(|field| field.sensitive).collect();
+    let idents: Vec<&Ident> = fields.iter().map(|field| field.ident).collect();
+    let getters: Vec<Ident> = fields.iter().map(|field| format_ident!("get_{}", field.ident)).collect();
+    let setters: Vec<Ident> = fields.iter().map(|field| format_ident!("set_{}", field.ident)).collect();
+    let types: Vec<&Type> = fields.iter().map(|field| field.ty).collect();
+    let defaults = fields.iter().map(|field| {
+        let (ty, key) = (field.ty, &field.key);
+        match &field.default {
+            Some(text) => quote! { ::prefstore::__private::parse_default::<#ty>(#key, #text) },
+            None => quote! { ::std::result::Result::Ok(<#ty as ::std::default::Default>::default()) },
+        }
+    });
+    let getter_docs = fields.iter().map(|field| format!("Reads the `{}` preference, or its default if it is not stored.", field.key));
+    let setter_docs = fields.iter().map(|field| format!("Stores the `{}` preference.", field.key));
+
+    Ok(quote! {
+        impl #name {
+            #(
+                #[doc = #getter_docs]
+                pub fn #getters(app_name: &str) -> ::std::io::Result<#types> {
+                    ::prefstore::__private::load_field::<#types>(app_name, #keys, #sensitive, || #defaults)
+                }
+
+                #[doc = #setter_docs]
+                pub fn #setters(app_name: &str, value: &#types) -> ::std::io::Result<()> {
+                    ::prefstore::__private::save_field(app_name, #keys, #sensitive, value)
+                }
+            )*
+        }
+
+        impl ::prefstore::Preferences for #name {
+            fn load(app_name: &str) -> ::std::io::Result<Self> {
+                ::std::result::Result::Ok(#name {
+                    #( #idents: #name::#getters(app_name)?, )*
+                })
+            }
+
+            fn save(&self, app_name: &str) -> ::std::io::Result<()> {
+                #( #name::#setters(app_name, &self.#idents)?; )*
+                ::std::result::Result::Ok(())
+            }
+
+            fn keys() -> &'static [&'static str] {
+                &[#(#keys),*]
+            }
+
+            fn sensitive_keys() -> &'static [&'static str] {
+                &[#(#sensitive_keys),*]
+            }
+        }
+
+        impl ::prefstore::FromPreferences for #name {
+            fn from_preferences(app_name: &str) -> ::std::io::Result<Self> {
+                <#name as ::prefstore::Preferences>::load(app_name)
+            }
+        }
+    })
+}
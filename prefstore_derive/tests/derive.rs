@@ -0,0 +1,93 @@
+use prefstore::{config_folder_path, getpreference, savecustom, savepreference, Preferences};
+
+#[derive(Debug, PartialEq, Preferences)]
+struct Settings {
+    #[pref(default = "light")]
+    theme: String,
+    #[pref(key = "font", default = 12)]
+    font_size: i32,
+    #[pref(default = true)]
+    autosave: bool,
+    recent_limit: u32,
+    #[pref(namespace = "sync", sensitive)]
+    token: String,
+}
+
+#[derive(Debug, PartialEq, Preferences)]
+#[pref(namespace = "window")]
+struct Window {
+    #[pref(default = 800)]
+    width: u32,
+    #[pref(default = 1.5)]
+    scale: f64,
+}
+
+fn fresh(app_name: &str) -> &str {
+    let _ = std::fs::remove_dir_all(config_folder_path(app_name).unwrap());
+    app_name
+}
+
+#[test]
+fn test_load_uses_defaults() {
+    let app_name = fresh("prefstore_derive_defaults");
+    let settings = Settings::load(app_name).unwrap();
+    assert_eq!(settings, Settings {
+        theme: "light".to_string(),
+        font_size: 12,
+        autosave: true,
+        recent_limit: 0,
+        token: String::new(),
+    });
+    assert_eq!(Window::load(app_name).unwrap(), Window { width: 800, scale: 1.5 });
+}
+
+#[test]
+fn test_save_and_load_round_trip() {
+    let app_name = fresh("prefstore_derive_round_trip");
+    let settings = Settings {
+        theme: "dark".to_string(),
+        font_size: 14,
+        autosave: false,
+        recent_limit: 5,
+        token: "abc".to_string(),
+    };
+    settings.save(app_name).unwrap();
+    assert_eq!(Settings::load(app_name).unwrap(), settings);
+
+    // Fields are ordinary preferences under their keys.
+    assert_eq!(getpreference(app_name, "font", 0), "14");
+    assert_eq!(getpreference(app_name, "sync/token", ""), "abc");
+
+    Window::set_width(app_name, &1024).unwrap();
+    assert_eq!(getpreference(app_name, "window/width", 0), "1024");
+    assert_eq!(Window::get_width(app_name).unwrap(), 1024);
+    savepreference(app_name, "theme", "solarized").unwrap();
+    assert_eq!(Settings::get_theme(app_name).unwrap(), "solarized");
+}
+
+#[test]
+fn test_keys() {
+    assert_eq!(Settings::keys(), ["theme", "font", "autosave", "recent_limit", "sync/token"]);
+    assert_eq!(Settings::sensitive_keys(), ["sync/token"]);
+    assert_eq!(Window::keys(), ["window/width", "window/scale"]);
+    assert!(Window::sensitive_keys().is_empty());
+}
+
+#[test]
+fn test_unparsable_value_is_an_error() {
+    let app_name = fresh("prefstore_derive_invalid");
+    savecustom(app_name, "font.txt", "large").unwrap();
+    let error = Settings::load(app_name).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(Settings::get_theme(app_name).unwrap(), "light");
+}
+
+#[test]
+fn test_sensitive_fields_are_masked() {
+    let app_name = fresh("prefstore_derive_sensitive");
+    let settings = Settings { token: "abc".to_string(), ..Settings::load(app_name).unwrap() };
+    settings.save(app_name).unwrap();
+    let all = prefstore::getallcustomkeyed(app_name, "", &["txt"], prefstore::Depth::Recursive).unwrap();
+    assert_eq!(all["sync/token.txt"], prefstore::REDACTED);
+    assert_eq!(all["theme.txt"], "light");
+}